@@ -0,0 +1,105 @@
+use book_merger::exchange_tools::{AggregatedBook, Level, OrderSide};
+use book_merger::snapshot::{self, SUMMARY_LEVELS};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rust_decimal::Decimal;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::{watch, RwLock};
+
+const SUBSCRIBERS: usize = 1000;
+const UPDATES: usize = 100;
+
+// prices per side of the books the subscribers read
+const DEPTH: i64 = 100;
+// prices per side of the book publishing is measured on
+const DEEP: i64 = 10000;
+
+fn book(depth: i64) -> AggregatedBook {
+  let mut book = AggregatedBook::new(String::from("ethbtc"));
+  for i in 1..=depth {
+    for (side, price) in [(OrderSide::Bid, 100000 - i), (OrderSide::Ask, 100000 + i)] {
+      for exchange in ["binance", "bitstamp"] {
+        book.insert_level(side, Level { exchange: exchange.to_owned(), price: Decimal::from(price), amount: Decimal::from(i) });
+      }
+    }
+  }
+  book
+}
+
+fn apply(book: &mut AggregatedBook, update: usize) {
+  book.insert_level(OrderSide::Bid, Level { exchange: String::from("binance"), price: Decimal::from(99999), amount: Decimal::from(update) });
+}
+
+// every subscriber reads the book under the lock and builds its own summary
+async fn locked_book() {
+  let book = Arc::new(RwLock::new(book(DEPTH)));
+  let (tx, rx) = watch::channel(0);
+  let subscribers: Vec<_> = (0..SUBSCRIBERS).map(|_| {
+    let (book, mut rx) = (book.clone(), rx.clone());
+    tokio::spawn(async move {
+      while rx.changed().await.is_ok() {
+        black_box(book.read().await.get_levels(SUMMARY_LEVELS));
+        if *rx.borrow() == UPDATES {
+          break;
+        }
+      }
+    })
+  }).collect();
+  for update in 1..=UPDATES {
+    apply(&mut *book.write().await, update);
+    tx.send(update).unwrap();
+    tokio::task::yield_now().await;
+  }
+  futures::future::join_all(subscribers).await;
+}
+
+// the writer publishes one snapshot per update, subscribers only load it
+async fn published_snapshots() {
+  let mut book = book(DEPTH);
+  let shared = snapshot::shared(book.clone());
+  let (tx, rx) = watch::channel(0);
+  let subscribers: Vec<_> = (0..SUBSCRIBERS).map(|_| {
+    let (shared, mut rx) = (shared.clone(), rx.clone());
+    tokio::spawn(async move {
+      while rx.changed().await.is_ok() {
+        black_box(shared.load().summary.clone());
+        if *rx.borrow() == UPDATES {
+          break;
+        }
+      }
+    })
+  }).collect();
+  for update in 1..=UPDATES {
+    apply(&mut book, update);
+    snapshot::publish(&shared, &book);
+    tx.send(update).unwrap();
+    tokio::task::yield_now().await;
+  }
+  futures::future::join_all(subscribers).await;
+}
+
+fn subscribers(c: &mut Criterion) {
+  let runtime = Runtime::new().unwrap();
+  let mut group = c.benchmark_group("1000 subscribers, 100 updates");
+  group.sample_size(20);
+  group.bench_function("rwlock", |b| b.to_async(&runtime).iter(locked_book));
+  group.bench_function("snapshot", |b| b.to_async(&runtime).iter(published_snapshots));
+  group.finish();
+
+  // the copy of the book and the summary built on every update
+  let mut group = c.benchmark_group("publish");
+  for depth in [DEPTH, DEEP] {
+    let mut book = book(depth);
+    let shared = snapshot::shared(book.clone());
+    let mut update = 0;
+    group.bench_function(format!("{} levels", depth), |b| b.iter(|| {
+      update += 1;
+      apply(&mut book, update);
+      snapshot::publish(&shared, &book);
+    }));
+  }
+  group.finish();
+}
+
+criterion_group!(benches, subscribers);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+use crate::alerts::{self, Alert, AlertKind};
+use crate::error::{AggregatorError, Error};
+use crate::exchange_tools::{AggregatedBook, OrderBook};
+use crate::snapshot::{self, SharedSnapshot};
+use crate::sync::VenueStatus;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+
+// commands to the aggregator task, the only owner of its book
+pub enum Command {
+  // a diff of a venue stream
+  Update(OrderBook),
+  // a whole venue book, REST or streamed, it replaces the levels of the venue
+  Snapshot(OrderBook),
+  // a copy of the current book
+  Query(oneshot::Sender<AggregatedBook>),
+  // sync state of the stream of an exchange
+  Status(String, VenueStatus),
+}
+
+#[derive(Clone)]
+pub struct AggregatorHandle {
+  commands: mpsc::Sender<Command>,
+  // exchanges whose book was dropped and has to be snapshotted again
+  resyncs: broadcast::Sender<String>,
+}
+
+impl AggregatorHandle {
+  async fn send(&self, command: Command) -> Result<(), Error> {
+    self.commands.send(command).await.map_err(|_| AggregatorError::Stopped.into())
+  }
+
+  pub async fn update(&self, orderbook: OrderBook) -> Result<(), Error> {
+    self.send(Command::Update(orderbook)).await
+  }
+
+  pub async fn snapshot(&self, orderbook: OrderBook) -> Result<(), Error> {
+    self.send(Command::Snapshot(orderbook)).await
+  }
+
+  pub async fn status(&self, exchange: String, status: VenueStatus) -> Result<(), Error> {
+    self.send(Command::Status(exchange, status)).await
+  }
+
+  pub async fn query(&self) -> Result<AggregatedBook, Error> {
+    let (tx, rx) = oneshot::channel();
+    self.send(Command::Query(tx)).await?;
+    rx.await.map_err(|_| AggregatorError::Stopped.into())
+  }
+
+  pub fn resyncs(&self) -> broadcast::Receiver<String> {
+    self.resyncs.subscribe()
+  }
+}
+
+// applies the commands one after another and publishes a snapshot after every
+// change, it never waits for the network
+pub struct Aggregator {
+  book: AggregatedBook,
+  snapshot: SharedSnapshot,
+  notifier: Arc<watch::Sender<bool>>,
+  alerts: broadcast::Sender<Alert>,
+  commands: mpsc::Receiver<Command>,
+  resyncs: broadcast::Sender<String>,
+}
+
+impl Aggregator {
+  pub fn new(
+    book: AggregatedBook,
+    snapshot: SharedSnapshot,
+    notifier: Arc<watch::Sender<bool>>,
+    alerts: broadcast::Sender<Alert>) -> (Self, AggregatorHandle) {
+    let (tx, commands) = mpsc::channel(100);
+    let (resyncs, _) = broadcast::channel(16);
+    let handle = AggregatorHandle { commands: tx, resyncs: resyncs.clone() };
+    (Self { book, snapshot, notifier, alerts, commands, resyncs }, handle)
+  }
+
+  fn publish(&self, events: Vec<AlertKind>) {
+    for event in events.iter() {
+      if let AlertKind::VenueCrossed(crossing) = event {
+        let _ = self.resyncs.send(crossing.bid_exchange.clone());
+      }
+    }
+    alerts::publish(&self.alerts, &self.book.currency_pair, events);
+    snapshot::publish(&self.snapshot, &self.book);
+    if let Err(e) = self.notifier.send(true) {
+      println!("{}", e);
+    }
+  }
+
+  pub async fn run(mut self) {
+    while let Some(command) = self.commands.recv().await {
+      match command {
+        Command::Update(orderbook) => {
+          let events = self.book.update(orderbook);
+          self.publish(events);
+        },
+        Command::Snapshot(orderbook) => {
+          let events = self.book.apply_snapshot(orderbook);
+          self.publish(events);
+        },
+        Command::Query(reply) => {
+          let _ = reply.send(self.book.clone());
+        },
+        // only a new state is published, changed counters go out with the next snapshot
+        Command::Status(exchange, status) => {
+          let changed = self.book.venues.get(&exchange).map(|s| s.state) != Some(status.state);
+          self.book.venues.insert(exchange, status);
+          if changed {
+            self.publish(Vec::new());
+          }
+        },
+      }
+    }
+  }
+}
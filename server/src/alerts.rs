@@ -0,0 +1,178 @@
+use crate::exchange_tools::AggregatedBook;
+use chrono::Utc;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::broadcast;
+
+// best bid of `bid_exchange` at or above the best ask of `ask_exchange`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Crossing {
+  pub bid_exchange: String,
+  pub ask_exchange: String,
+  pub best_bid: Decimal,
+  pub best_ask: Decimal,
+}
+
+impl Crossing {
+  // bid and ask at the same price
+  pub fn locked(&self) -> bool {
+    self.best_bid == self.best_ask
+  }
+}
+
+// a configured rule and the value of its metric when it fired or resolved
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuleAlert {
+  pub rule: String,
+  pub value: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum AlertKind {
+  // a venue book crossed in itself is corrupt, it is dropped and the venue resynced
+  VenueCrossed(Crossing),
+  // venues crossed against each other, an arbitrage window of the market
+  MarketCrossed(Crossing),
+  MarketUncrossed(Crossing),
+  RuleFired(RuleAlert),
+  RuleResolved(RuleAlert),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+  pub symbol: String,
+  // milliseconds since the epoch
+  pub time: i64,
+  pub kind: AlertKind,
+}
+
+pub fn publish(alerts: &broadcast::Sender<Alert>, symbol: &str, events: Vec<AlertKind>) {
+  let time = Utc::now().timestamp_millis();
+  for kind in events {
+    let _ = alerts.send(Alert { symbol: symbol.to_owned(), time, kind });
+  }
+}
+
+// best bid and best ask of every venue in the merged book
+#[derive(Debug, Default)]
+pub struct Tops {
+  pub bids: BTreeMap<String, Decimal>,
+  pub asks: BTreeMap<String, Decimal>,
+}
+
+// first price of every venue walking a side from its best price, the walk
+// ends once all `venues` are found
+fn side_tops<'a>(levels: impl Iterator<Item = (&'a Decimal, &'a HashMap<String, Decimal>)>, venues: usize) -> BTreeMap<String, Decimal> {
+  let mut tops = BTreeMap::new();
+  for (price, amounts) in levels {
+    for (exchange, amount) in amounts.iter() {
+      if !amount.is_zero() && !tops.contains_key(exchange) {
+        tops.insert(exchange.clone(), *price);
+      }
+    }
+    if tops.len() == venues {
+      break;
+    }
+  }
+  tops
+}
+
+impl Tops {
+  // the venues of the book are those that merged a venue book into it
+  pub fn of(book: &AggregatedBook) -> Self {
+    let venues = book.venue_updates.len();
+    Self {
+      bids: side_tops(book.bids.iter().rev(), venues),
+      asks: side_tops(book.asks.iter(), venues),
+    }
+  }
+
+  pub fn remove(&mut self, exchange: &str) {
+    self.bids.remove(exchange);
+    self.asks.remove(exchange);
+  }
+
+  // venues whose own best bid is at or above their own best ask
+  pub fn venue_crossings(&self) -> Vec<Crossing> {
+    self.bids.iter()
+      .filter_map(|(exchange, best_bid)| {
+        let best_ask = self.asks.get(exchange)?;
+        (best_bid >= best_ask).then(|| Crossing {
+          bid_exchange: exchange.clone(),
+          ask_exchange: exchange.clone(),
+          best_bid: *best_bid,
+          best_ask: *best_ask,
+        })
+      })
+      .collect()
+  }
+
+  // the widest crossing between the tops of two different venues
+  pub fn market_crossing(&self) -> Option<Crossing> {
+    let mut crossing: Option<Crossing> = None;
+    for (bid_exchange, best_bid) in self.bids.iter() {
+      for (ask_exchange, best_ask) in self.asks.iter() {
+        let wider = match &crossing {
+          Some(c) => best_bid - best_ask > c.best_bid - c.best_ask,
+          None => true,
+        };
+        if bid_exchange != ask_exchange && best_bid >= best_ask && wider {
+          crossing = Some(Crossing {
+            bid_exchange: bid_exchange.clone(),
+            ask_exchange: ask_exchange.clone(),
+            best_bid: *best_bid,
+            best_ask: *best_ask,
+          });
+        }
+      }
+    }
+    crossing
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{AggregatedBook, Level, OrderBook, OrderSide, VenueUpdate};
+  use super::{AlertKind, Tops};
+  use rust_decimal_macros::dec;
+
+  fn level(exchange: &str, price: rust_decimal::Decimal) -> Level {
+    Level { exchange: exchange.to_owned(), price, amount: dec!(1) }
+  }
+
+  #[test]
+  fn crossed_books() {
+    let corrupt = OrderBook {
+      exchange: String::from("binance"),
+      bids: vec![level("binance", dec!(101))],
+      asks: vec![level("binance", dec!(100))],
+      update: VenueUpdate::default(),
+    };
+    let mut book = AggregatedBook::new(String::from("ethbtc"));
+    let events = book.update(corrupt);
+    assert!(matches!(&events[0], AlertKind::VenueCrossed(c) if c.best_bid == dec!(101)));
+    assert!(book.bids.is_empty() && book.asks.is_empty());
+
+    let venue_book = |exchange: &str, bid, ask| OrderBook {
+      exchange: exchange.to_owned(),
+      bids: vec![level(exchange, bid)],
+      asks: vec![level(exchange, ask)],
+      update: VenueUpdate::default(),
+    };
+    book.update(venue_book("binance", dec!(100), dec!(101)));
+    let events = book.update(venue_book("bitstamp", dec!(99), dec!(100)));
+    let crossing = Tops::of(&book).market_crossing().unwrap();
+    assert_eq!(events, vec![AlertKind::MarketCrossed(crossing.clone())]);
+    assert_eq!((crossing.bid_exchange.as_str(), crossing.ask_exchange.as_str()), ("binance", "bitstamp"));
+    assert!(crossing.locked());
+
+    // diffs that cross only with the levels already merged
+    let diff = |bids, asks| OrderBook { exchange: String::from("bitstamp"), bids, asks, update: VenueUpdate::default() };
+    assert!(book.update(diff(vec![], vec![level("bitstamp", dec!(101))])).is_empty());
+    let events = book.update(diff(vec![level("bitstamp", dec!(100.5))], vec![]));
+    assert!(matches!(&events[0], AlertKind::VenueCrossed(c) if c.bid_exchange == "bitstamp" && c.best_ask == dec!(100)));
+    assert!(book.venue_levels(OrderSide::Ask, "bitstamp").is_empty());
+    assert_eq!(book.venue_levels(OrderSide::Ask, "binance"), vec![(dec!(101), dec!(1))]);
+  }
+}
@@ -0,0 +1,150 @@
+use crate::exchange_tools::{AggregatedBook, OrderSide};
+use crate::fees::{taker_fee, FeeSchedules};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Opportunity {
+  pub buy_exchange: String,
+  pub sell_exchange: String,
+  pub buy_price: Decimal,
+  pub sell_price: Decimal,
+  pub amount: Decimal,
+  pub gross_profit: Decimal,
+  pub net_profit: Decimal,
+}
+
+// buys on the asks of `buy_exchange` and sells into the bids of `sell_exchange`
+// for as long as the crossing stays profitable after taker fees on both legs
+fn cross_venues(book: &AggregatedBook, buy_exchange: &str, sell_exchange: &str, fees: &FeeSchedules) -> Option<Opportunity> {
+  let buy_fee = taker_fee(fees, buy_exchange);
+  let sell_fee = taker_fee(fees, sell_exchange);
+  let mut asks = book.venue_levels(OrderSide::Ask, buy_exchange).into_iter().peekable();
+  let mut bids = book.venue_levels(OrderSide::Bid, sell_exchange).into_iter().peekable();
+  let (buy_price, sell_price) = match (asks.peek(), bids.peek()) {
+    (Some(ask), Some(bid)) => (ask.0, bid.0),
+    _ => return None,
+  };
+  let (mut amount, mut cost, mut proceeds, mut paid_fees) = (dec!(0), dec!(0), dec!(0), dec!(0));
+  let (mut ask_left, mut bid_left) = (dec!(0), dec!(0));
+  let (mut ask_price, mut bid_price) = (buy_price, sell_price);
+  loop {
+    if ask_left.is_zero() {
+      match asks.next() {
+        Some((price, qty)) => { ask_price = price; ask_left = qty; },
+        None => break,
+      }
+    }
+    if bid_left.is_zero() {
+      match bids.next() {
+        Some((price, qty)) => { bid_price = price; bid_left = qty; },
+        None => break,
+      }
+    }
+    if bid_price * (dec!(1) - sell_fee) <= ask_price * (dec!(1) + buy_fee) {
+      break;
+    }
+    let qty = ask_left.min(bid_left);
+    amount += qty;
+    cost += qty * ask_price;
+    proceeds += qty * bid_price;
+    paid_fees += qty * ask_price * buy_fee + qty * bid_price * sell_fee;
+    ask_left -= qty;
+    bid_left -= qty;
+  }
+  if amount.is_zero() {
+    return None;
+  }
+  Some(Opportunity {
+    buy_exchange: buy_exchange.to_owned(),
+    sell_exchange: sell_exchange.to_owned(),
+    buy_price,
+    sell_price,
+    amount,
+    gross_profit: proceeds - cost,
+    net_profit: proceeds - cost - paid_fees,
+  })
+}
+
+pub fn detect(book: &AggregatedBook, fees: &FeeSchedules) -> Vec<Opportunity> {
+  let exchanges = book.exchanges();
+  let mut opportunities = Vec::new();
+  for buy_exchange in exchanges.iter() {
+    for sell_exchange in exchanges.iter().filter(|e| *e != buy_exchange) {
+      if let Some(opportunity) = cross_venues(book, buy_exchange, sell_exchange, fees) {
+        opportunities.push(opportunity);
+      }
+    }
+  }
+  opportunities
+}
+
+// passes the opportunities of a venue pair only when they differ from the last one sent
+#[derive(Default)]
+pub struct OpportunityFilter {
+  last: HashMap<(String, String), Opportunity>,
+}
+
+impl OpportunityFilter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn changed(&mut self, opportunities: Vec<Opportunity>) -> Vec<Opportunity> {
+    let mut current = HashMap::new();
+    let mut changed = Vec::new();
+    for opportunity in opportunities {
+      let pair = (opportunity.buy_exchange.clone(), opportunity.sell_exchange.clone());
+      if self.last.get(&pair) != Some(&opportunity) {
+        changed.push(opportunity.clone());
+      }
+      current.insert(pair, opportunity);
+    }
+    // a closed opportunity is sent again when it reopens
+    self.last = current;
+    changed
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{AggregatedBook, Level, OrderSide};
+  use crate::fees::{FeeSchedule, FeeSchedules};
+  use super::{detect, OpportunityFilter};
+  use rust_decimal_macros::dec;
+
+  fn level(exchange: &str, price: rust_decimal::Decimal, amount: rust_decimal::Decimal) -> Level {
+    Level { exchange: exchange.to_owned(), price, amount }
+  }
+
+  #[test]
+  fn crossed_venues() {
+    let mut book = AggregatedBook::new(String::from("ethbtc"));
+    book.insert_level(OrderSide::Bid, level("binance", dec!(101), dec!(1)));
+    book.insert_level(OrderSide::Bid, level("binance", dec!(100.5), dec!(2)));
+    book.insert_level(OrderSide::Ask, level("binance", dec!(102), dec!(1)));
+    book.insert_level(OrderSide::Bid, level("bitstamp", dec!(99), dec!(1)));
+    book.insert_level(OrderSide::Ask, level("bitstamp", dec!(100), dec!(1.5)));
+    book.insert_level(OrderSide::Ask, level("bitstamp", dec!(100.8), dec!(5)));
+
+    let opportunities = detect(&book, &FeeSchedules::new());
+    assert_eq!(opportunities.len(), 1);
+    let opportunity = &opportunities[0];
+    assert_eq!(opportunity.buy_exchange, "bitstamp");
+    assert_eq!(opportunity.sell_exchange, "binance");
+    // 1 @ 100 -> 101, 0.5 @ 100 -> 100.5, stops at 100.8 > 100.5
+    assert_eq!(opportunity.amount, dec!(1.5));
+    assert_eq!(opportunity.gross_profit, dec!(1.25));
+    assert_eq!(opportunity.net_profit, dec!(1.25));
+
+    let mut filter = OpportunityFilter::new();
+    assert_eq!(filter.changed(opportunities.clone()).len(), 1);
+    assert!(filter.changed(opportunities.clone()).is_empty());
+    assert!(filter.changed(Vec::new()).is_empty());
+    assert_eq!(filter.changed(opportunities).len(), 1);
+
+    let fees = FeeSchedules::from([(String::from("binance"), FeeSchedule { maker: dec!(0), taker: dec!(0.01) })]);
+    assert!(detect(&book, &fees).is_empty());
+  }
+}
@@ -7,4 +7,31 @@ pub struct OrderBook {
   pub lastUpdateId: Decimal,
   pub bids: Vec<(Decimal, Decimal)>,
   pub asks: Vec<(Decimal, Decimal)>
-}
\ No newline at end of file
+}
+
+// diff of the book between update ids U and u, a zero amount removes the level
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DepthUpdate {
+  pub e: String,
+  pub E: i64,
+  pub s: String,
+  pub U: i64,
+  pub u: i64,
+  pub b: Vec<(Decimal, Decimal)>,
+  pub a: Vec<(Decimal, Decimal)>,
+}
+
+// taker side is sell when the buyer is the maker
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Trade {
+  pub e: String,
+  pub E: i64,
+  pub s: String,
+  pub t: i64,
+  pub p: Decimal,
+  pub q: Decimal,
+  pub T: i64,
+  pub m: bool,
+}
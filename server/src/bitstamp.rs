@@ -16,3 +16,21 @@ pub struct Event {
   pub channel: String,
   pub data: OrderBook,
 }
+
+// type is 0 for a buy and 1 for a sell taker
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Trade {
+  pub id: i64,
+  pub price_str: Decimal,
+  pub amount_str: Decimal,
+  #[serde(rename = "type")]
+  pub kind: i32,
+  pub microtimestamp: Decimal,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TradeEvent {
+  pub event: String,
+  pub channel: String,
+  pub data: Trade,
+}
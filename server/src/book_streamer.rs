@@ -1,11 +1,33 @@
+use crate::aggregator::{Aggregator, AggregatorHandle};
+use crate::alerts::{self, Alert, AlertKind, Crossing, RuleAlert};
+use crate::arbitrage::{self, Opportunity, OpportunityFilter};
+use crate::candles::{self, Candle, CandleBuilder, CandleSource, CLOSE_MARGIN};
 use crate::connector::connect_exchange;
-use crate::client::error::Error;
-use crate::exchange_tools::{AggregatedBook, Exchange, Summary, Level};
+use crate::error::{self, Error, ServerError, Subsystem};
+use crate::exchange_tools::{rest_client, AggregatedBook, ConsolidatedLevel, Exchange, Summary, Level, Side, MIN_BUCKET};
+use crate::execution::{self, ExecutionEstimate, QuantityUnit, VenueFill};
+use crate::paper::{Fill, OrderStatus, PaperEngine, PaperOrder};
+use crate::router::{self, ChildOrder, ParentOrder, RoutePlan, VenueConstraints};
+use crate::rules::{self, Rule, RulesEngine};
+use crate::snapshot::{self, BookSnapshot, SharedSnapshot, SNAPSHOT_LEVELS, SUMMARY_LEVELS};
+use crate::exporter::{export_snapshots, ExportConfig};
+use crate::fees::FeeSchedules;
+use crate::instruments::{split_pair, Instrument};
+use crate::metrics::{BookMetrics, MetricsConfig};
+use crate::sync::{SyncState, VenueStatus};
+use crate::synthetic::{self, SyntheticDefinition};
+use crate::tls::TlsConfig;
+use crate::trades::{merge_trades, Trade};
+use chrono::Utc;
 use futures::try_join;
+use http::HeaderValue;
 use num_traits::cast::ToPrimitive;
 use proto::orderbook_aggregator_server::{OrderbookAggregatorServer, OrderbookAggregator};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock, watch};
+use tokio::sync::{broadcast, mpsc, RwLock, watch};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic_web::GrpcWebLayer;
 use tonic::{transport::Server, Request, Response, Status};
@@ -17,110 +39,995 @@ mod proto {
 }
 
 pub struct BookStreamerTonik {
-  pub aggregator: Arc<RwLock<AggregatedBook>>,
-  pub watcher: Arc<RwLock<watch::Receiver<bool>>>,
+  pub snapshot: SharedSnapshot,
+  // notified after every published snapshot, cloned by each subscriber
+  pub watcher: watch::Receiver<bool>,
+  pub fees: Arc<FeeSchedules>,
+  pub paper: Arc<RwLock<PaperEngine>>,
+  // every served pair, including the one of `snapshot`
+  pub books: Arc<HashMap<String, SharedSnapshot>>,
+  pub synthetics: Arc<Vec<SyntheticDefinition>>,
+  pub trades: broadcast::Sender<Trade>,
+  pub candles: Arc<RwLock<CandleBuilder>>,
+  // closed candles of every source and interval
+  pub closed_candles: broadcast::Sender<Candle>,
+  pub alerts: broadcast::Sender<Alert>,
+}
+
+// a further pair merged from its own exchange streams
+pub struct Market {
+  pub currency_pair: String,
+  pub exchanges: Vec<(Exchange, Option<String>)>,
+  pub instruments: Vec<Instrument>,
 }
 
 pub struct BookStreamer {
   pub exchanges: Vec<(Exchange, Option<String>)>,
-  pub aggregator: Arc<RwLock<AggregatedBook>>
+  // moved into the aggregator task by `run`
+  pub aggregator: AggregatedBook,
+  pub export: Option<ExportConfig>,
+  pub fees: FeeSchedules,
+  pub metrics: MetricsConfig,
+  pub instruments: Vec<Instrument>,
+  pub markets: Vec<Market>,
+  pub synthetics: Vec<SyntheticDefinition>,
+  // trade streams of the pair, no trades are merged when empty
+  pub trade_exchanges: Vec<(Exchange, Option<String>)>,
+  // evaluated against the book of the pair
+  pub rules: Vec<Rule>,
+  // url rule alerts are posted to
+  pub webhook: Option<String>,
+  pub tls: Option<TlsConfig>,
 }
 
 impl BookStreamer {
   pub fn new(exchanges: Vec<(Exchange, Option<String>)>, currency_pair: String) -> Self {
     Self {
       exchanges,
-      aggregator: Arc::new(RwLock::new(AggregatedBook::new(currency_pair))),
+      aggregator: AggregatedBook::new(currency_pair),
+      export: None,
+      fees: FeeSchedules::new(),
+      metrics: MetricsConfig::default(),
+      instruments: Vec::new(),
+      markets: Vec::new(),
+      synthetics: Vec::new(),
+      trade_exchanges: Vec::new(),
+      rules: Vec::new(),
+      webhook: None,
+      tls: None,
+    }
+  }
+}
+
+struct SummaryOptions {
+  fee_adjusted: bool,
+  bucket_size: Decimal,
+  bucket_bps: Decimal,
+  levels: usize,
+  consolidated: bool,
+  // quote currency the prices are converted into
+  convert_to: Option<String>,
+}
+
+impl SummaryOptions {
+  // the summary built with every snapshot fits the request
+  fn plain(&self) -> bool {
+    !self.fee_adjusted && !self.consolidated && self.convert_to.is_none()
+      && self.bucket_size.is_zero() && self.bucket_bps.is_zero() && self.levels == SUMMARY_LEVELS
+  }
+
+  fn summarize_snapshot(&self, snapshot: &BookSnapshot, fees: &FeeSchedules, rate: Option<Decimal>) -> Summary {
+    if self.plain() {
+      snapshot.summary.clone()
+    } else {
+      let mut summary = self.summarize(&snapshot.book, fees, rate);
+      summary.publish_time = snapshot.summary.publish_time;
+      summary
+    }
+  }
+
+  fn summarize(&self, source: &AggregatedBook, fees: &FeeSchedules, rate: Option<Decimal>) -> Summary {
+    let book = source;
+    let adjusted;
+    let book = if self.fee_adjusted {
+      adjusted = book.fee_adjusted(fees);
+      &adjusted
+    } else {
+      book
+    };
+    let converted;
+    let book = match rate {
+      Some(rate) => {
+        converted = book.converted(rate);
+        &converted
+      },
+      None => book,
+    };
+    let bucket = if self.bucket_size.is_zero() { book.bps_bucket(self.bucket_bps) } else { self.bucket_size };
+    let bucketed;
+    // a bucket finer than the book, i.e. few bps of a tiny mid, leaves the prices as they are
+    let book = if bucket < book.min_bucket() {
+      book
+    } else {
+      bucketed = book.bucketed(bucket);
+      &bucketed
+    };
+    let mut summary = if self.consolidated {
+      book.get_consolidated_levels(self.levels)
+    } else {
+      book.get_levels(self.levels)
+    };
+    // the derived books start without the updates of the source
+    source.stamp(&mut summary);
+    summary
+  }
+}
+
+enum BookSource {
+  Book(SharedSnapshot),
+  Synthetic(SyntheticDefinition),
+}
+
+// served pair between the two currencies and whether its mid has to be inverted
+fn conversion_book(books: &HashMap<String, SharedSnapshot>, from: &str, to: &str) -> Option<(SharedSnapshot, bool)> {
+  books.iter().find_map(|(pair, book)| {
+    let (base, quote) = split_pair(pair);
+    if base == from && quote == to {
+      Some((book.clone(), false))
+    } else if base == to && quote == from {
+      Some((book.clone(), true))
+    } else {
+      None
     }
+  })
+}
+
+// live price of one unit of the quote currency in the target currency,
+// None while the conversion pair has no mid price
+fn conversion_rate(book: &SharedSnapshot, inverted: bool) -> Option<Decimal> {
+  let mid = book.load().book.metrics.mid;
+  match (mid.is_zero(), inverted) {
+    (true, _) => None,
+    (false, false) => Some(mid),
+    (false, true) => Some(Decimal::ONE / mid),
   }
 }
 
-fn get_prop_levels(levels: &Vec<Level>) -> Vec<proto::Level> {
+// decimals go out as doubles, one that has none is logged, counted and sent as 0
+fn double(value: Decimal) -> f64 {
+  value.to_f64().unwrap_or_else(|| {
+    error::recover("encoding reply", &ServerError::NoDouble(value).into());
+    0.0
+  })
+}
+
+fn get_prop_levels(levels: &[Level]) -> Vec<proto::Level> {
   levels.iter()
     .map(|l|
       proto::Level{
               exchange: l.exchange.clone(),
-              price: l.price.to_f64().unwrap(),
-              amount: l.amount.to_f64().unwrap(),
+              price: double(l.price),
+              amount: double(l.amount),
+              price_str: l.price.to_string(),
+              amount_str: l.amount.to_string(),
           })
       .collect()
 }
 
 impl From<Summary> for proto::Summary {
   fn from(summary: Summary) -> Self {
-      let spread = summary.spread.to_f64().unwrap();
+      let spread = double(summary.spread);
+      let spread_str = summary.spread.to_string();
       let bids: Vec<proto::Level> = get_prop_levels(&summary.bids);
       let asks: Vec<proto::Level> = get_prop_levels(&summary.asks);
-      proto::Summary{ spread, bids, asks }
+      let metrics = Some(proto::Metrics::from(summary.metrics));
+      let consolidated_bids = get_prop_consolidated_levels(&summary.consolidated_bids);
+      let consolidated_asks = get_prop_consolidated_levels(&summary.consolidated_asks);
+      let publish_time = summary.publish_time;
+      let venues = summary.venues.into_iter()
+        .map(|(exchange, venue)| proto::Venue {
+          exchange,
+          update_id: venue.update.update_id,
+          exchange_time: venue.update.exchange_time,
+          receive_time: venue.update.receive_time,
+          state: venue.state.map(proto_sync_state).unwrap_or(proto::SyncState::Connecting) as i32,
+          update_age: if venue.update.receive_time == 0 { 0 } else { (publish_time - venue.update.receive_time).max(0) },
+          included: venue.included,
+        })
+        .collect();
+      proto::Summary{
+        spread, bids, asks, metrics, consolidated_bids, consolidated_asks, spread_str,
+        sequence: summary.sequence,
+        publish_time: summary.publish_time,
+        venues,
+      }
   }
 } 
 
+fn get_prop_consolidated_levels(levels: &[ConsolidatedLevel]) -> Vec<proto::ConsolidatedLevel> {
+  levels.iter()
+    .map(|l|
+      proto::ConsolidatedLevel{
+        price: double(l.price),
+        amount: double(l.amount),
+        venues: l.venues.iter().map(|(exchange, amount)| (exchange.clone(), double(*amount))).collect(),
+        price_str: l.price.to_string(),
+        amount_str: l.amount.to_string(),
+      })
+    .collect()
+}
+
+impl From<BookMetrics> for proto::Metrics {
+  fn from(metrics: BookMetrics) -> Self {
+    proto::Metrics {
+      mid: double(metrics.mid),
+      micro_price: double(metrics.micro_price),
+      imbalance: double(metrics.imbalance),
+      bid_depth: double(metrics.bid_depth),
+      ask_depth: double(metrics.ask_depth),
+      bid_vwap: double(metrics.bid_vwap),
+      ask_vwap: double(metrics.ask_vwap),
+    }
+  }
+}
+
+impl From<Opportunity> for proto::Opportunity {
+  fn from(opportunity: Opportunity) -> Self {
+    proto::Opportunity {
+      buy_exchange: opportunity.buy_exchange,
+      sell_exchange: opportunity.sell_exchange,
+      buy_price: double(opportunity.buy_price),
+      sell_price: double(opportunity.sell_price),
+      amount: double(opportunity.amount),
+      gross_profit: double(opportunity.gross_profit),
+      net_profit: double(opportunity.net_profit),
+    }
+  }
+}
+
+impl From<VenueFill> for proto::VenueFill {
+  fn from(fill: VenueFill) -> Self {
+    proto::VenueFill {
+      average_price: double(fill.average_price()),
+      exchange: fill.exchange,
+      amount: double(fill.amount),
+      notional: double(fill.notional),
+    }
+  }
+}
+
+impl From<ExecutionEstimate> for proto::ExecutionCostReply {
+  fn from(estimate: ExecutionEstimate) -> Self {
+    proto::ExecutionCostReply {
+      filled: double(estimate.filled),
+      notional: double(estimate.notional),
+      average_price: double(estimate.average_price),
+      worst_price: double(estimate.worst_price),
+      mid: double(estimate.mid),
+      slippage_bps: double(estimate.slippage_bps),
+      complete: estimate.complete,
+      venues: estimate.venues.into_iter().map(proto::VenueFill::from).collect(),
+    }
+  }
+}
+
+impl From<proto::Side> for Side {
+  fn from(side: proto::Side) -> Self {
+    match side {
+      proto::Side::Buy => Side::Buy,
+      proto::Side::Sell => Side::Sell,
+    }
+  }
+}
+
+impl From<Side> for proto::Side {
+  fn from(side: Side) -> Self {
+    match side {
+      Side::Buy => proto::Side::Buy,
+      Side::Sell => proto::Side::Sell,
+    }
+  }
+}
+
+// rpc helpers fail with the status the rpc returns, large as it is
+#[allow(clippy::result_large_err)]
+fn to_decimal(value: f64, field: &str) -> Result<Decimal, Status> {
+  Decimal::from_f64(value).ok_or_else(|| Status::invalid_argument(format!("bad {}", field)))
+}
+
+#[allow(clippy::result_large_err)]
+fn to_optional_decimal(value: Option<f64>, field: &str) -> Result<Option<Decimal>, Status> {
+  match value {
+    Some(value) => to_decimal(value, field).map(Some),
+    None => Ok(None),
+  }
+}
+
+impl TryFrom<proto::VenueConstraints> for VenueConstraints {
+  type Error = Status;
+  #[allow(clippy::result_large_err)]
+  fn try_from(venue: proto::VenueConstraints) -> Result<Self, Status> {
+    Ok(VenueConstraints {
+      min_size: to_decimal(venue.min_size, "min_size")?,
+      tick_size: to_decimal(venue.tick_size, "tick_size")?,
+      lot_size: to_decimal(venue.lot_size, "lot_size")?,
+      balance: to_optional_decimal(venue.balance, "balance")?,
+    })
+  }
+}
+
+impl From<ChildOrder> for proto::ChildOrder {
+  fn from(order: ChildOrder) -> Self {
+    proto::ChildOrder {
+      exchange: order.exchange,
+      side: proto::Side::from(order.side) as i32,
+      price: double(order.price),
+      quantity: double(order.quantity),
+    }
+  }
+}
+
+impl From<RoutePlan> for proto::RoutePlanReply {
+  fn from(plan: RoutePlan) -> Self {
+    proto::RoutePlanReply {
+      orders: plan.orders.into_iter().map(proto::ChildOrder::from).collect(),
+      unallocated: double(plan.unallocated),
+    }
+  }
+}
+
+impl From<Fill> for proto::PaperFill {
+  fn from(fill: Fill) -> Self {
+    proto::PaperFill {
+      exchange: fill.exchange,
+      price: double(fill.price),
+      quantity: double(fill.quantity),
+      fee: double(fill.fee),
+      maker: fill.maker,
+    }
+  }
+}
+
+impl From<Trade> for proto::Trade {
+  fn from(trade: Trade) -> Self {
+    proto::Trade {
+      exchange: trade.exchange,
+      price: double(trade.price),
+      size: double(trade.size),
+      side: proto::Side::from(trade.side) as i32,
+      trade_time: trade.trade_time,
+      receive_time: trade.receive_time,
+    }
+  }
+}
+
+impl From<proto::CandleSource> for CandleSource {
+  fn from(source: proto::CandleSource) -> Self {
+    match source {
+      proto::CandleSource::Trades => CandleSource::Trades,
+      proto::CandleSource::Mid => CandleSource::Mid,
+    }
+  }
+}
+
+impl From<Candle> for proto::Candle {
+  fn from(candle: Candle) -> Self {
+    let source = match candle.source {
+      CandleSource::Trades => proto::CandleSource::Trades,
+      CandleSource::Mid => proto::CandleSource::Mid,
+    };
+    proto::Candle {
+      interval: candle.interval,
+      source: source as i32,
+      start_time: candle.start_time,
+      open: double(candle.open),
+      high: double(candle.high),
+      low: double(candle.low),
+      close: double(candle.close),
+      volume: double(candle.volume),
+      venue_volumes: candle.venue_volumes.into_iter().map(|(k, v)| (k, double(v))).collect(),
+      vwap: double(candle.vwap),
+      count: candle.count,
+    }
+  }
+}
+
+impl From<Crossing> for proto::Crossing {
+  fn from(crossing: Crossing) -> Self {
+    proto::Crossing {
+      locked: crossing.locked(),
+      bid_exchange: crossing.bid_exchange,
+      ask_exchange: crossing.ask_exchange,
+      best_bid: double(crossing.best_bid),
+      best_ask: double(crossing.best_ask),
+    }
+  }
+}
+
+impl From<RuleAlert> for proto::RuleAlert {
+  fn from(alert: RuleAlert) -> Self {
+    proto::RuleAlert {
+      rule: alert.rule,
+      value: double(alert.value),
+    }
+  }
+}
+
+impl From<Alert> for proto::Alert {
+  fn from(alert: Alert) -> Self {
+    let event = match alert.kind {
+      AlertKind::VenueCrossed(crossing) => proto::alert::Event::VenueCrossed(crossing.into()),
+      AlertKind::MarketCrossed(crossing) => proto::alert::Event::MarketCrossed(crossing.into()),
+      AlertKind::MarketUncrossed(crossing) => proto::alert::Event::MarketUncrossed(crossing.into()),
+      AlertKind::RuleFired(alert) => proto::alert::Event::RuleFired(alert.into()),
+      AlertKind::RuleResolved(alert) => proto::alert::Event::RuleResolved(alert.into()),
+    };
+    proto::Alert {
+      symbol: alert.symbol,
+      time: alert.time,
+      event: Some(event),
+    }
+  }
+}
+
+// evaluates the rules four times a second, so stale venues are noticed without updates
+async fn evaluate_rules(rules: Vec<Rule>, snapshot: SharedSnapshot, alerts: broadcast::Sender<Alert>) -> Result<(), Error> {
+  let mut engine = RulesEngine::new(rules, Utc::now().timestamp_millis());
+  let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(250));
+  loop {
+    interval.tick().await;
+    let snapshot = snapshot.load();
+    let events = engine.evaluate(&snapshot.book, Utc::now().timestamp_millis());
+    alerts::publish(&alerts, &snapshot.book.currency_pair, events);
+  }
+}
+
+// posts the fired and resolved rule alerts, a failed or hung post is logged and dropped
+async fn notify_webhook(url: String, mut alerts: broadcast::Receiver<Alert>) -> Result<(), Error> {
+  loop {
+    match alerts.recv().await {
+      Ok(alert) if matches!(alert.kind, AlertKind::RuleFired(_) | AlertKind::RuleResolved(_)) => {
+        if let Err(e) = rules::post_webhook(rest_client(), &url, &alert).await {
+          error::recover(&format!("webhook {} failed", url), &e);
+        }
+      },
+      Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {},
+      Err(broadcast::error::RecvError::Closed) => return Ok(()),
+    }
+  }
+}
+
+fn proto_sync_state(state: SyncState) -> proto::SyncState {
+  match state {
+    SyncState::Connecting => proto::SyncState::Connecting,
+    SyncState::AwaitingSnapshot => proto::SyncState::AwaitingSnapshot,
+    SyncState::Synced => proto::SyncState::Synced,
+    SyncState::Resyncing => proto::SyncState::Resyncing,
+  }
+}
+
+fn venue_sync(exchange: &str, status: &VenueStatus) -> proto::VenueSync {
+  proto::VenueSync {
+    exchange: exchange.to_owned(),
+    state: proto_sync_state(status.state) as i32,
+    last_update_id: status.last_update_id,
+    gaps: status.gaps,
+    resyncs: status.resyncs,
+    buffered: status.buffered as u64,
+  }
+}
+
+#[allow(clippy::result_large_err)]
+fn candle_series(interval: &str, source: i32) -> Result<(String, CandleSource), Status> {
+  if !candles::INTERVALS.contains(&interval) {
+    return Err(Status::invalid_argument(format!("interval must be one of {:?}", candles::INTERVALS)));
+  }
+  let source = proto::CandleSource::from_i32(source)
+    .ok_or_else(|| Status::invalid_argument("unknown candle source"))?;
+  Ok((interval.to_owned(), source.into()))
+}
+
+impl From<PaperOrder> for proto::PaperOrder {
+  fn from(order: PaperOrder) -> Self {
+    let status = match order.status {
+      OrderStatus::Open => proto::OrderStatus::Open,
+      OrderStatus::PartiallyFilled => proto::OrderStatus::PartiallyFilled,
+      OrderStatus::Filled => proto::OrderStatus::Filled,
+      OrderStatus::Cancelled => proto::OrderStatus::Cancelled,
+    };
+    proto::PaperOrder {
+      id: order.id,
+      account: order.account,
+      side: proto::Side::from(order.side) as i32,
+      quantity: double(order.quantity),
+      limit_price: order.limit_price.map(double),
+      filled: double(order.filled),
+      status: status as i32,
+      queue_ahead: double(order.queue_ahead),
+      fills: order.fills.into_iter().map(proto::PaperFill::from).collect(),
+    }
+  }
+}
+
 #[tonic::async_trait]
 impl OrderbookAggregator for BookStreamerTonik {
   type BookSummaryStream = ReceiverStream<Result<proto::Summary, Status>>;
     async fn book_summary(
         &self,
-        request: Request<proto::Empty>,
+        request: Request<proto::SummaryRequest>,
     ) -> Result<Response<Self::BookSummaryStream>, Status> {
       println!("Got a request from {:?}", request.remote_addr());
       let (tx, rx) = mpsc::channel::<Result<proto::Summary, Status>>(100);
-      let agg = self.aggregator.clone();
-      let watcher = self.watcher.clone();
+      let mut watcher = self.watcher.clone();
+      let fees = self.fees.clone();
+      let books = self.books.clone();
+      let request = request.into_inner();
+      let symbol = request.symbol.to_ascii_lowercase();
+      let source = if symbol.is_empty() {
+        BookSource::Book(self.snapshot.clone())
+      } else if let Some(book) = books.get(&symbol) {
+        BookSource::Book(book.clone())
+      } else if let Some(definition) = self.synthetics.iter().find(|d| d.symbol == symbol) {
+        BookSource::Synthetic(definition.clone())
+      } else {
+        return Err(Status::not_found(format!("unknown symbol {}", symbol)));
+      };
+      let options = SummaryOptions {
+        fee_adjusted: request.fee_adjusted,
+        bucket_size: to_decimal(request.bucket_size, "bucket_size")?,
+        bucket_bps: to_decimal(request.bucket_bps, "bucket_bps")?,
+        levels: if request.levels == 0 { SUMMARY_LEVELS } else { (request.levels as usize).min(SNAPSHOT_LEVELS) },
+        consolidated: request.consolidated,
+        convert_to: request.convert_to.map(|c| c.to_ascii_lowercase()),
+      };
+      if options.bucket_size < Decimal::ZERO || options.bucket_bps < Decimal::ZERO {
+        return Err(Status::invalid_argument("bucket must not be negative"));
+      }
+      let min_bucket = match &source {
+        BookSource::Book(snapshot) => snapshot.load().book.min_bucket(),
+        BookSource::Synthetic(_) => MIN_BUCKET,
+      };
+      if !options.bucket_size.is_zero() && options.bucket_size < min_bucket {
+        return Err(Status::invalid_argument(format!("bucket_size must be at least {}", min_bucket)));
+      }
+      let pair = if symbol.is_empty() { self.snapshot.load().book.currency_pair.clone() } else { symbol.clone() };
+      let quote = split_pair(&pair).1;
+      let conversion = match &options.convert_to {
+        Some(target) if *target != quote => Some(conversion_book(&books, &quote, target)
+          .ok_or_else(|| Status::not_found(format!("no served pair between {} and {}", quote, target)))?),
+        _ => None,
+      };
       tokio::spawn(async move {
-        while let Ok(_) = watcher.read().await.clone().changed().await {
-          let aggregation = agg.read().await;
-          let summary = aggregation.get_levels(10);
-          let _ = tx.send(Ok(proto::Summary::from(summary))).await;
+        while watcher.changed().await.is_ok() {
+          let rate = match &conversion {
+            Some((book, inverted)) => match conversion_rate(book, *inverted) {
+              Some(rate) => Some(rate),
+              None => continue,
+            },
+            None => None,
+          };
+          let summary = match &source {
+            BookSource::Book(snapshot) => options.summarize_snapshot(&snapshot.load(), &fees, rate),
+            BookSource::Synthetic(definition) => match synthetic::build_from(definition, &books) {
+              Some(book) => {
+                let mut summary = options.summarize(&book, &fees, rate);
+                summary.publish_time = Utc::now().timestamp_millis();
+                summary
+              },
+              None => continue,
+            },
+          };
+          if tx.send(Ok(proto::Summary::from(summary))).await.is_err() {
+            return;
+          }
         }
       });
       Ok(Response::new(ReceiverStream::new(rx)))
   } 
+
+  type ArbitrageOpportunitiesStream = ReceiverStream<Result<proto::Opportunity, Status>>;
+    async fn arbitrage_opportunities(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<Self::ArbitrageOpportunitiesStream>, Status> {
+      println!("Got an arbitrage request from {:?}", request.remote_addr());
+      let (tx, rx) = mpsc::channel::<Result<proto::Opportunity, Status>>(100);
+      let snapshot = self.snapshot.clone();
+      let mut watcher = self.watcher.clone();
+      let fees = self.fees.clone();
+      tokio::spawn(async move {
+        let mut filter = OpportunityFilter::new();
+        while watcher.changed().await.is_ok() {
+          let opportunities = filter.changed(arbitrage::detect(&snapshot.load().book, &fees));
+          for opportunity in opportunities {
+            if tx.send(Ok(proto::Opportunity::from(opportunity))).await.is_err() {
+              return;
+            }
+          }
+        }
+      });
+      Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+    async fn execution_cost(
+        &self,
+        request: Request<proto::ExecutionCostRequest>,
+    ) -> Result<Response<proto::ExecutionCostReply>, Status> {
+      let request = request.into_inner();
+      let side = proto::Side::from_i32(request.side)
+        .ok_or_else(|| Status::invalid_argument("unknown side"))?;
+      let quantity = to_decimal(request.quantity, "quantity")?;
+      if quantity <= Decimal::ZERO {
+        return Err(Status::invalid_argument("quantity must be positive"));
+      }
+      let unit = if request.quote_quantity { QuantityUnit::Quote } else { QuantityUnit::Base };
+      let estimate = execution::estimate(&self.snapshot.load().book, side.into(), quantity, unit);
+      Ok(Response::new(estimate.into()))
+  }
+
+    async fn route_plan(
+        &self,
+        request: Request<proto::RouteRequest>,
+    ) -> Result<Response<proto::RoutePlanReply>, Status> {
+      let request = request.into_inner();
+      let side = proto::Side::from_i32(request.side)
+        .ok_or_else(|| Status::invalid_argument("unknown side"))?;
+      let quantity = to_decimal(request.quantity, "quantity")?;
+      if quantity <= Decimal::ZERO {
+        return Err(Status::invalid_argument("quantity must be positive"));
+      }
+      let order = ParentOrder {
+        side: side.into(),
+        quantity,
+        limit_price: to_optional_decimal(request.limit_price, "limit_price")?,
+      };
+      let snapshot = self.snapshot.load();
+      let mut constraints: HashMap<String, VenueConstraints> = snapshot.book.instruments.iter()
+        .map(|(exchange, instrument)| (exchange.clone(), instrument.into()))
+        .collect();
+      for venue in request.venues {
+        let exchange = venue.exchange.to_ascii_lowercase();
+        let venue = VenueConstraints::try_from(venue)?;
+        if let Some(field) = venue.negative() {
+          return Err(Status::invalid_argument(format!("{} of {} must not be negative", field, exchange)));
+        }
+        constraints.insert(exchange, venue);
+      }
+      let plan = router::plan(&snapshot.book, &order, &constraints);
+      Ok(Response::new(plan.into()))
+  }
+
+    async fn submit_paper_order(
+        &self,
+        request: Request<proto::PaperOrderRequest>,
+    ) -> Result<Response<proto::PaperOrder>, Status> {
+      let request = request.into_inner();
+      let side = proto::Side::from_i32(request.side)
+        .ok_or_else(|| Status::invalid_argument("unknown side"))?;
+      let quantity = to_decimal(request.quantity, "quantity")?;
+      if quantity <= Decimal::ZERO {
+        return Err(Status::invalid_argument("quantity must be positive"));
+      }
+      let limit_price = to_optional_decimal(request.limit_price, "limit_price")?;
+      let snapshot = self.snapshot.load_full();
+      let order = self.paper.write().await.submit(&snapshot.book, request.account, side.into(), quantity, limit_price);
+      Ok(Response::new(order.into()))
+  }
+
+    async fn cancel_paper_order(
+        &self,
+        request: Request<proto::CancelPaperOrderRequest>,
+    ) -> Result<Response<proto::PaperOrder>, Status> {
+      let request = request.into_inner();
+      match self.paper.write().await.cancel(&request.account, request.order_id) {
+        Some(order) => Ok(Response::new(order.into())),
+        None => Err(Status::not_found("unknown order")),
+      }
+  }
+
+    async fn paper_position(
+        &self,
+        request: Request<proto::PaperPositionRequest>,
+    ) -> Result<Response<proto::PaperPositionReply>, Status> {
+      let account = request.into_inner().account;
+      let mid = self.snapshot.load().book.metrics.mid;
+      let paper = self.paper.read().await;
+      let position = paper.position(&account);
+      Ok(Response::new(proto::PaperPositionReply {
+        position: double(position.base),
+        average_price: double(position.average_price),
+        realized_pnl: double(position.realized_pnl),
+        unrealized_pnl: double(position.unrealized_pnl(mid)),
+        fees: double(position.fees),
+        open_orders: paper.open_orders(&account).into_iter().map(proto::PaperOrder::from).collect(),
+        account,
+      }))
+  }
+
+  type TradeStreamStream = ReceiverStream<Result<proto::Trade, Status>>;
+    async fn trade_stream(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<Self::TradeStreamStream>, Status> {
+      println!("Got a trade request from {:?}", request.remote_addr());
+      let (tx, rx) = mpsc::channel::<Result<proto::Trade, Status>>(100);
+      let mut trades = self.trades.subscribe();
+      tokio::spawn(async move {
+        loop {
+          match trades.recv().await {
+            Ok(trade) => if tx.send(Ok(trade.into())).await.is_err() {
+              return;
+            },
+            Err(broadcast::error::RecvError::Lagged(skipped)) => println!("trade subscriber skipped {} trades", skipped),
+            Err(broadcast::error::RecvError::Closed) => return,
+          }
+        }
+      });
+      Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  type BookAlertsStream = ReceiverStream<Result<proto::Alert, Status>>;
+    async fn book_alerts(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<Self::BookAlertsStream>, Status> {
+      println!("Got an alerts request from {:?}", request.remote_addr());
+      let (tx, rx) = mpsc::channel::<Result<proto::Alert, Status>>(100);
+      let mut alerts = self.alerts.subscribe();
+      tokio::spawn(async move {
+        loop {
+          match alerts.recv().await {
+            Ok(alert) => if tx.send(Ok(alert.into())).await.is_err() {
+              return;
+            },
+            Err(broadcast::error::RecvError::Lagged(skipped)) => println!("alert subscriber skipped {} alerts", skipped),
+            Err(broadcast::error::RecvError::Closed) => return,
+          }
+        }
+      });
+      Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  type CandleStreamStream = ReceiverStream<Result<proto::Candle, Status>>;
+    async fn candle_stream(
+        &self,
+        request: Request<proto::CandleRequest>,
+    ) -> Result<Response<Self::CandleStreamStream>, Status> {
+      let request = request.into_inner();
+      let (interval, source) = candle_series(&request.interval, request.source)?;
+      let (tx, rx) = mpsc::channel::<Result<proto::Candle, Status>>(100);
+      let mut closed = self.closed_candles.subscribe();
+      tokio::spawn(async move {
+        loop {
+          match closed.recv().await {
+            Ok(candle) if candle.source == source && candle.interval == interval => {
+              if tx.send(Ok(candle.into())).await.is_err() {
+                return;
+              }
+            },
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {},
+            Err(broadcast::error::RecvError::Closed) => return,
+          }
+        }
+      });
+      Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+    async fn candle_history(
+        &self,
+        request: Request<proto::CandleHistoryRequest>,
+    ) -> Result<Response<proto::CandleHistoryReply>, Status> {
+      let request = request.into_inner();
+      let (interval, source) = candle_series(&request.interval, request.source)?;
+      let end_time = if request.end_time == 0 { i64::MAX } else { request.end_time };
+      let candles = self.candles.read().await
+        .candles(source, &interval, request.start_time, end_time)
+        .unwrap_or_default();
+      Ok(Response::new(proto::CandleHistoryReply {
+        candles: candles.into_iter().map(proto::Candle::from).collect(),
+      }))
+  }
+
+    async fn venue_status(
+        &self,
+        request: Request<proto::VenueStatusRequest>,
+    ) -> Result<Response<proto::VenueStatusReply>, Status> {
+      let symbol = request.into_inner().symbol.to_ascii_lowercase();
+      let snapshot = if symbol.is_empty() {
+        self.snapshot.load_full()
+      } else {
+        self.books.get(&symbol)
+          .ok_or_else(|| Status::not_found(format!("unknown symbol {}", symbol)))?
+          .load_full()
+      };
+      let mut venues: Vec<_> = snapshot.book.venues.iter()
+        .map(|(exchange, status)| venue_sync(exchange, status))
+        .collect();
+      venues.sort_by(|a, b| a.exchange.cmp(&b.exchange));
+      Ok(Response::new(proto::VenueStatusReply {
+        venues,
+        recovered_errors: Subsystem::ALL.iter()
+          .map(|subsystem| (subsystem.name().to_owned(), error::recovered(*subsystem)))
+          .collect(),
+      }))
+  }
+}
+
+// feeds trades and the mid of every book update into the candles. Trade
+// candles close once the trades released by the merger passed their end, mid
+// candles once a second, both also without a later price
+async fn build_candles(
+  candles: Arc<RwLock<CandleBuilder>>,
+  publisher: broadcast::Sender<Candle>,
+  mut trades: broadcast::Receiver<Trade>,
+  mut released: watch::Receiver<i64>,
+  mut watcher: watch::Receiver<bool>,
+  snapshot: SharedSnapshot) -> Result<(), Error> {
+  let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
+  let mut merging = true;
+  loop {
+    let closed = tokio::select! {
+      // the trades published before a release are added before it closes their candles
+      biased;
+      trade = trades.recv() => match trade {
+        Ok(trade) => candles.write().await.on_trade(&trade),
+        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+      },
+      changed = released.changed(), if merging => {
+        if changed.is_err() {
+          merging = false;
+          continue;
+        }
+        let until = released.borrow().saturating_sub(CLOSE_MARGIN);
+        candles.write().await.close_until(CandleSource::Trades, until)
+      },
+      changed = watcher.changed() => {
+        if changed.is_err() {
+          return Ok(());
+        }
+        let mid = snapshot.load().book.metrics.mid;
+        if mid.is_zero() {
+          continue;
+        }
+        candles.write().await.on_mid(Utc::now().timestamp_millis(), mid)
+      },
+      _ = interval.tick() => candles.write().await.close_until(CandleSource::Mid, Utc::now().timestamp_millis()),
+    };
+    for candle in closed {
+      let _ = publisher.send(candle);
+    }
+  }
 }
 
 use itertools::Itertools;
 
+async fn merge_market(exchanges: Vec<(Exchange, Option<String>)>, aggregator: Aggregator, handle: AggregatorHandle) {
+  for (exchange, subscriber) in exchanges {
+    let handle = handle.clone();
+    tokio::spawn(async move {
+      if let Err(e) = connect_exchange(exchange, subscriber, handle).await {
+        println!("book stream closed: {}", e);
+      }
+    });
+  }
+  aggregator.run().await;
+}
+
+// paper orders follow the published snapshots of the book
+async fn follow_book(paper: Arc<RwLock<PaperEngine>>, snapshot: SharedSnapshot, mut watcher: watch::Receiver<bool>) {
+  while watcher.changed().await.is_ok() {
+    let current = snapshot.load_full();
+    paper.write().await.on_book_update(&current.book);
+  }
+}
+
 impl BookStreamer {
   pub async fn run(&mut self) -> Result<(), Error> {
-    let (tx, mut rx) = mpsc::channel(100);
-    let tx2 = tx.clone();
-    let (exchange1, exchange2) = self.exchanges.clone().into_iter().collect_tuple().unwrap();
-    let aggregator = self.aggregator.clone();
-    let aggregator_ = self.aggregator.clone();
-    let aggregator__ = self.aggregator.clone();
-    let aggregator___ = self.aggregator.clone();
+    self.aggregator.metrics_config = self.metrics.clone();
+    self.aggregator.set_instruments(self.instruments.clone());
+    let (exchange1, exchange2) = self.exchanges.clone().into_iter().collect_tuple()
+      .ok_or_else(|| ServerError::BadConfig(format!("two exchanges expected, got {}", self.exchanges.len())))?;
+    // a bad certificate fails the start, not the spawned server
+    let addr: std::net::SocketAddr = "[::1]:50051".parse()?;
+    let mut builder = Server::builder();
+    if let Some(tls) = &self.tls {
+      builder = builder.tls_config(tls.server_config()?)?;
+      println!("Server listening on {} with tls", addr);
+    } else {
+      println!("Server listening on {}", addr);
+    }
+    let snapshot = snapshot::shared(self.aggregator.clone());
+    let snapshot_export = snapshot.clone();
+    let export = self.export.clone();
+    let fees = Arc::new(self.fees.clone());
+    let paper = Arc::new(RwLock::new(PaperEngine::new(self.fees.clone())));
     let (tx_w, rx_w)= watch::channel(false);
+    let tx_w = Arc::new(tx_w);
+    let (alerts, _) = broadcast::channel(1000);
+    let (aggregator, handle) = Aggregator::new(self.aggregator.clone(), snapshot.clone(), tx_w.clone(), alerts.clone());
+    let handle_ = handle.clone();
+    let paper_follower = tokio::spawn(follow_book(paper.clone(), snapshot.clone(), rx_w.clone()));
+    let rules = tokio::spawn(evaluate_rules(self.rules.clone(), snapshot.clone(), alerts.clone()));
+    let webhook = self.webhook.clone();
+    let webhook_alerts = alerts.subscribe();
+    let mut books = HashMap::from([(self.aggregator.currency_pair.to_ascii_lowercase(), snapshot.clone())]);
+    let mut markets = Vec::new();
+    for market in std::mem::take(&mut self.markets) {
+      let mut market_book = AggregatedBook::new(market.currency_pair.clone());
+      market_book.metrics_config = self.metrics.clone();
+      market_book.set_instruments(market.instruments);
+      let market_snapshot = snapshot::shared(market_book.clone());
+      books.insert(market.currency_pair.to_ascii_lowercase(), market_snapshot.clone());
+      let (market_aggregator, market_handle) = Aggregator::new(market_book, market_snapshot, tx_w.clone(), alerts.clone());
+      markets.push(tokio::spawn(merge_market(market.exchanges, market_aggregator, market_handle)));
+    }
+    let books = Arc::new(books);
+    let synthetics = Arc::new(self.synthetics.clone());
+    let (trades, _) = broadcast::channel(1000);
+    let trades_ = trades.clone();
+    let trade_exchanges = self.trade_exchanges.clone();
+    // a day of one second candles
+    let candles = Arc::new(RwLock::new(CandleBuilder::new(24 * 60 * 60)));
+    let (closed_candles, _) = broadcast::channel(1000);
+    let (released, released_rx) = watch::channel(i64::MIN);
+    let candle_builder = tokio::spawn(build_candles(
+      candles.clone(), closed_candles.clone(), trades.subscribe(), released_rx, rx_w.clone(), snapshot.clone()));
     match try_join!(
-      tokio::spawn(async move { connect_exchange(exchange1.clone().0, exchange1.1.clone(), tx, aggregator__).await }),
-      tokio::spawn(async move { connect_exchange(exchange2.clone().0, exchange2.1.clone(), tx2, aggregator___).await }),
-      tokio::spawn(async move { 
-        while let Some(res) = rx.recv().await {
-          let mut aggregator_guard = aggregator_.write().await;
-          aggregator_guard.update(res).await;
-           match tx_w.send(true) {
-            Err(e) => println!("{}", e),
-            _ => {}
-          }
-        }
-    }),
+      tokio::spawn(async move { connect_exchange(exchange1.0, exchange1.1, handle).await }),
+      tokio::spawn(async move { connect_exchange(exchange2.0, exchange2.1, handle_).await }),
+      tokio::spawn(aggregator.run()),
+      paper_follower,
     tokio::spawn(async move { 
-      let addr = "[::1]:50051".parse().unwrap();
-      println!("Server listening on {}", addr);
-      Server::builder()
+      builder
       .accept_http1(true)
       .layer(
           CorsLayer::new()
           .allow_headers(Any)
-          .allow_origin(["http://localhost:8080".parse().unwrap()])
+          .allow_origin([HeaderValue::from_static("http://localhost:8080")])
           .expose_headers(Any)
       )
       .layer(GrpcWebLayer::new())
-      .add_service(OrderbookAggregatorServer::new(BookStreamerTonik { aggregator, watcher: Arc::new(RwLock::new(rx_w)) }))
+      .add_service(OrderbookAggregatorServer::new(BookStreamerTonik {
+        snapshot,
+        watcher: rx_w,
+        fees,
+        paper,
+        books,
+        synthetics,
+        trades,
+        candles,
+        closed_candles,
+        alerts,
+      }))
       .serve(addr)
-      .await
-    })
+      .await?;
+      Ok::<(), Error>(())
+    }),
+    tokio::spawn(async move {
+      match export {
+        Some(config) => export_snapshots(config, snapshot_export).await,
+        None => Ok(()),
+      }
+    }),
+    tokio::spawn(async move {
+      if trade_exchanges.is_empty() {
+        return Ok(());
+      }
+      merge_trades(trade_exchanges, trades_, released).await
+    }),
+    candle_builder,
+    rules,
+    tokio::spawn(async move {
+      match webhook {
+        Some(url) => notify_webhook(url, webhook_alerts).await,
+        None => Ok(()),
+      }
+    }),
+    futures::future::try_join_all(markets)
   ) {
      Ok(_) => Ok(()),
-     Err(v) => Err(Error::JoinError(v)),
+     Err(v) => Err(v.into()),
   }
   }
 }
\ No newline at end of file
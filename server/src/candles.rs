@@ -0,0 +1,229 @@
+use crate::trades::Trade;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+pub const INTERVALS: [&str; 4] = ["1s", "1m", "5m", "1h"];
+// milliseconds trade candles stay open after the released trades passed their end
+pub const CLOSE_MARGIN: i64 = 250;
+
+// trade candles carry volumes, mid candles sample the mid price of the merged book
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandleSource {
+  Trades,
+  Mid,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candle {
+  pub source: CandleSource,
+  pub interval: String,
+  // milliseconds since the epoch, inclusive
+  pub start_time: i64,
+  pub open: Decimal,
+  pub high: Decimal,
+  pub low: Decimal,
+  pub close: Decimal,
+  pub volume: Decimal,
+  pub venue_volumes: BTreeMap<String, Decimal>,
+  pub vwap: Decimal,
+  // trades or mid samples
+  pub count: u64,
+  notional: Decimal,
+}
+
+impl Candle {
+  fn new(source: CandleSource, interval: &str, start_time: i64, price: Decimal) -> Self {
+    Self {
+      source,
+      interval: interval.to_owned(),
+      start_time,
+      open: price,
+      high: price,
+      low: price,
+      close: price,
+      volume: Decimal::ZERO,
+      venue_volumes: BTreeMap::new(),
+      vwap: price,
+      count: 0,
+      notional: Decimal::ZERO,
+    }
+  }
+
+  fn add(&mut self, price: Decimal, volume: Option<(&str, Decimal)>) {
+    self.high = self.high.max(price);
+    self.low = self.low.min(price);
+    self.close = price;
+    self.count += 1;
+    if let Some((exchange, size)) = volume {
+      self.volume += size;
+      self.notional += price * size;
+      *self.venue_volumes.entry(exchange.to_owned()).or_default() += size;
+      if !self.volume.is_zero() {
+        self.vwap = self.notional / self.volume;
+      }
+    }
+  }
+}
+
+// "1s", "1m", "5m", "1h" in milliseconds
+pub fn interval_millis(interval: &str) -> Option<i64> {
+  let (count, unit) = interval.split_at(interval.len().checked_sub(1)?);
+  let count: i64 = count.parse().ok().filter(|c| *c > 0)?;
+  let unit = match unit {
+    "s" => 1000,
+    "m" => 60 * 1000,
+    "h" => 60 * 60 * 1000,
+    _ => return None,
+  };
+  Some(count * unit)
+}
+
+struct Series {
+  millis: i64,
+  current: Option<Candle>,
+  closed: VecDeque<Candle>,
+  // start of the last closed candle, a candle is never opened twice
+  last_closed: Option<i64>,
+}
+
+// open candle and a bounded history of closed candles per source and interval
+pub struct CandleBuilder {
+  history: usize,
+  series: HashMap<(CandleSource, String), Series>,
+}
+
+impl CandleBuilder {
+  pub fn new(history: usize) -> Self {
+    let mut series = HashMap::new();
+    for source in [CandleSource::Trades, CandleSource::Mid] {
+      for interval in INTERVALS {
+        let millis = interval_millis(interval).unwrap();
+        series.insert((source, interval.to_owned()), Series { millis, current: None, closed: VecDeque::new(), last_closed: None });
+      }
+    }
+    Self { history, series }
+  }
+
+  // adds a price to every interval of the source and returns the candles it closed,
+  // prices older than the open candle or of a closed candle are dropped
+  fn add(&mut self, source: CandleSource, time: i64, price: Decimal, volume: Option<(&str, Decimal)>) -> Vec<Candle> {
+    let mut closed = Vec::new();
+    for ((series_source, interval), series) in self.series.iter_mut() {
+      if *series_source != source {
+        continue;
+      }
+      let start_time = time - time.rem_euclid(series.millis);
+      if series.last_closed.is_some_and(|last| start_time <= last) {
+        continue;
+      }
+      match series.current.as_ref().map(|c| c.start_time) {
+        Some(current) if current > start_time => continue,
+        Some(current) if current < start_time => {
+          let candle = series.current.take().unwrap();
+          Self::push_closed(series, candle.clone(), self.history);
+          closed.push(candle);
+        },
+        _ => {},
+      }
+      series.current
+        .get_or_insert_with(|| Candle::new(source, interval, start_time, price))
+        .add(price, volume);
+    }
+    closed
+  }
+
+  fn push_closed(series: &mut Series, candle: Candle, history: usize) {
+    series.last_closed = Some(candle.start_time);
+    series.closed.push_back(candle);
+    while series.closed.len() > history {
+      series.closed.pop_front();
+    }
+  }
+
+  pub fn on_trade(&mut self, trade: &Trade) -> Vec<Candle> {
+    self.add(CandleSource::Trades, trade.trade_time, trade.price, Some((trade.exchange.as_str(), trade.size)))
+  }
+
+  pub fn on_mid(&mut self, time: i64, mid: Decimal) -> Vec<Candle> {
+    self.add(CandleSource::Mid, time, mid, None)
+  }
+
+  // closes the open candles of the source that ended before `now`, also without a later price
+  pub fn close_until(&mut self, source: CandleSource, now: i64) -> Vec<Candle> {
+    let mut closed = Vec::new();
+    for ((series_source, _), series) in self.series.iter_mut() {
+      if *series_source == source && matches!(&series.current, Some(candle) if candle.start_time + series.millis <= now) {
+        let candle = series.current.take().unwrap();
+        Self::push_closed(series, candle.clone(), self.history);
+        closed.push(candle);
+      }
+    }
+    closed
+  }
+
+  // candles starting within [from, to], the open candle included, None for an unknown interval
+  pub fn candles(&self, source: CandleSource, interval: &str, from: i64, to: i64) -> Option<Vec<Candle>> {
+    let series = self.series.get(&(source, interval.to_owned()))?;
+    Some(series.closed.iter()
+      .chain(series.current.iter())
+      .filter(|c| c.start_time >= from && c.start_time <= to)
+      .cloned()
+      .collect())
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::Side;
+  use crate::trades::Trade;
+  use super::{interval_millis, CandleBuilder, CandleSource};
+  use rust_decimal_macros::dec;
+
+  #[test]
+  fn trade_and_mid_candles() {
+    assert_eq!(interval_millis("5m"), Some(300000));
+    assert_eq!(interval_millis("m"), None);
+
+    let trade = |exchange: &str, price, size, trade_time| Trade {
+      exchange: exchange.to_owned(), price, size, side: Side::Buy, trade_time, receive_time: trade_time,
+    };
+    let mut builder = CandleBuilder::new(10);
+    assert!(builder.on_trade(&trade("binance", dec!(10), dec!(1), 60000)).is_empty());
+    builder.on_trade(&trade("bitstamp", dec!(12), dec!(3), 60500));
+    builder.on_trade(&trade("binance", dec!(9), dec!(1), 60900));
+    let closed = builder.on_trade(&trade("binance", dec!(11), dec!(1), 61000));
+    assert_eq!(closed.len(), 1);
+    let candle = &closed[0];
+    assert_eq!(candle.interval, "1s");
+    assert_eq!((candle.open, candle.high, candle.low, candle.close), (dec!(10), dec!(12), dec!(9), dec!(9)));
+    assert_eq!(candle.volume, dec!(5));
+    assert_eq!(candle.venue_volumes["bitstamp"], dec!(3));
+    assert_eq!(candle.vwap, dec!(11));
+
+    let minute = builder.candles(CandleSource::Trades, "1m", 0, 120000).unwrap();
+    assert_eq!(minute[0].volume, dec!(6));
+    assert_eq!(builder.close_until(CandleSource::Trades, 120000).len(), 2);
+
+    builder.on_mid(1000, dec!(100));
+    builder.on_mid(1500, dec!(101));
+    let mid = builder.candles(CandleSource::Mid, "1s", 0, 2000).unwrap();
+    assert_eq!((mid[0].open, mid[0].close, mid[0].volume), (dec!(100), dec!(101), dec!(0)));
+    assert!(builder.close_until(CandleSource::Trades, 3000).is_empty());
+    assert_eq!(builder.close_until(CandleSource::Mid, 3000).len(), 1);
+  }
+
+  #[test]
+  fn late_trade() {
+    let trade = |price, trade_time| Trade {
+      exchange: String::from("binance"), price, size: dec!(1), side: Side::Buy, trade_time, receive_time: trade_time,
+    };
+    let mut builder = CandleBuilder::new(10);
+    builder.on_trade(&trade(dec!(10), 1000));
+    assert_eq!(builder.close_until(CandleSource::Trades, 2000).len(), 1);
+    // held back by the merger past the close of its candle
+    assert!(builder.on_trade(&trade(dec!(11), 1900)).is_empty());
+    let seconds = builder.candles(CandleSource::Trades, "1s", 0, 2000).unwrap();
+    assert_eq!(seconds.len(), 1);
+    assert_eq!(seconds[0].close, dec!(10));
+  }
+}
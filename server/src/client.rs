@@ -1,58 +1,54 @@
+use clap::{Arg, App};
 use proto::orderbook_aggregator_client::OrderbookAggregatorClient;
+use std::path::PathBuf;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
 pub mod error {
-  use tokio_tungstenite::tungstenite;
+  use std::fmt;
+
+  // errors of the grpc client, server errors live in crate::error
   #[derive(Debug)]
   pub enum Error {
-    BadConnection(tungstenite::Error),
-    BadData(serde_json::Error),
-    IoError(std::io::Error),
-    ServerError(tonic::transport::Error),
-    BadAddr(std::net::AddrParseError),
-    JoinError(tokio::task::JoinError),
-    NotImplemented(),
-    Status(tonic::Status),
-    OutdatedUpdate(),
+    Transport(tonic::transport::Error),
+    // boxed, a status is large
+    Status(Box<tonic::Status>),
+    Io(std::io::Error),
   }
 
-  impl From<tonic::Status> for Error {
-    fn from(e: tonic::Status) -> Self {
-      Self::Status(e)
+  impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      match self {
+        Self::Transport(e) => write!(f, "transport failed: {}", e),
+        Self::Status(status) => write!(f, "request failed: {}", status),
+        Self::Io(e) => write!(f, "io failed: {}", e),
+      }
     }
   }
 
-  impl From<tungstenite::Error> for Error {
-    fn from(e: tungstenite::Error) -> Self {
-      Self::BadConnection(e)
+  impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+      match self {
+        Self::Transport(e) => Some(e),
+        Self::Status(e) => Some(e.as_ref()),
+        Self::Io(e) => Some(e),
+      }
     }
   }
 
-  impl From<serde_json::Error> for Error {
-    fn from(e: serde_json::Error) -> Self {
-      Self::BadData(e)
-    }
-  }
-
-  impl From<std::io::Error> for Error {
-    fn from(e: std::io::Error) -> Self {
-      Self::IoError(e)
+  impl From<tonic::Status> for Error {
+    fn from(e: tonic::Status) -> Self {
+      Self::Status(Box::new(e))
     }
   }
 
   impl From<tonic::transport::Error> for Error {
     fn from(e: tonic::transport::Error) -> Self {
-      Self::ServerError(e)
-    }
-  }
-
-  impl From<std::net::AddrParseError> for Error {
-    fn from(e: std::net::AddrParseError) -> Self {
-      Self::BadAddr(e)
+      Self::Transport(e)
     }
   }
 
-  impl From<tokio::task::JoinError> for Error {
-    fn from(e:tokio::task::JoinError) -> Self {
-      Self::JoinError(e)
+  impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+      Self::Io(e)
     }
   }
 }
@@ -62,9 +58,47 @@ mod proto {
   tonic::include_proto!("book_merger");
 }
 
-pub async fn grpc_client() -> Result<(), error::Error> {
-  let mut client = OrderbookAggregatorClient::connect("http://[::1]:50051").await?;
-  let request = tonic::Request::new(proto::Empty {});
+// the server to stream from, over tls when a CA is given
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+  pub addr: String,
+  pub ca: Option<PathBuf>,
+  // certificate and key for servers verifying their clients
+  pub identity: Option<(PathBuf, PathBuf)>,
+  // name the server certificate is checked against, the host of `addr` otherwise
+  pub domain: Option<String>,
+}
+
+impl Default for ClientConfig {
+  fn default() -> Self {
+    Self { addr: String::from("[::1]:50051"), ca: None, identity: None, domain: None }
+  }
+}
+
+impl ClientConfig {
+  fn tls_config(&self) -> Result<Option<ClientTlsConfig>, error::Error> {
+    let ca = match &self.ca {
+      Some(ca) => ca,
+      None => return Ok(None),
+    };
+    let mut config = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+    if let Some((cert, key)) = &self.identity {
+      config = config.identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+    }
+    if let Some(domain) = &self.domain {
+      config = config.domain_name(domain);
+    }
+    Ok(Some(config))
+  }
+}
+
+pub async fn grpc_client(config: ClientConfig) -> Result<(), error::Error> {
+  let endpoint = match config.tls_config()? {
+    Some(tls) => Endpoint::from_shared(format!("https://{}", config.addr))?.tls_config(tls)?,
+    None => Endpoint::from_shared(format!("http://{}", config.addr))?,
+  };
+  let mut client = OrderbookAggregatorClient::new(endpoint.connect().await?);
+  let request = tonic::Request::new(proto::SummaryRequest::default());
   let mut response = client.book_summary(request).await?.into_inner();
   while let Some(res) = response.message().await? {
      println!("{:?}", res);
@@ -75,5 +109,43 @@ pub async fn grpc_client() -> Result<(), error::Error> {
 #[allow(dead_code)]
 #[tokio::main]
 async fn main() -> Result<(), error::Error> {
-  grpc_client().await
+  let matches = App::new("book-merger-client")
+    .about("stream the merged book summary")
+    .arg(Arg::new("addr")
+      .long("addr")
+      .takes_value(true)
+      .help("server address, [::1]:50051 by default")
+    )
+    .arg(Arg::new("ca")
+      .long("ca")
+      .takes_value(true)
+      .help("pem of the CA the server certificate is signed by, connects over tls")
+    )
+    .arg(Arg::new("cert")
+      .long("cert")
+      .takes_value(true)
+      .requires_all(&["key", "ca"])
+      .help("pem of the client certificate for servers verifying clients")
+    )
+    .arg(Arg::new("key")
+      .long("key")
+      .takes_value(true)
+      .requires("cert")
+      .help("pem of the client private key")
+    )
+    .arg(Arg::new("domain")
+      .long("domain")
+      .takes_value(true)
+      .help("name the server certificate is checked against, i.e. localhost")
+    )
+    .get_matches();
+  let mut config = ClientConfig::default();
+  if let Some(addr) = matches.value_of("addr") {
+    config.addr = addr.to_owned();
+  }
+  config.ca = matches.value_of("ca").map(PathBuf::from);
+  config.identity = matches.value_of("cert").zip(matches.value_of("key"))
+    .map(|(cert, key)| (PathBuf::from(cert), PathBuf::from(key)));
+  config.domain = matches.value_of("domain").map(String::from);
+  grpc_client(config).await
 }
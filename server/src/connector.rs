@@ -1,43 +1,178 @@
-use crate::exchange_tools::{Exchange, parse_book, OrderBook, AggregatedBook};
+use crate::aggregator::AggregatorHandle;
+use crate::error::{self, Error, ParseError};
+use crate::exchange_tools::{Exchange, fetch_snapshot, parse_book, OrderBook};
+use crate::sync::{Outcome, SyncState, VenueStatus, VenueSync};
 use futures_util::{StreamExt, SinkExt};
-use num_traits::cast::ToPrimitive;
-use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message, tungstenite::error::Error};
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use url::Url;
 
+// a staleness resync, not a gap: a venue is snapshotted again when its stream
+// is silent for this long, as a quiet pair may also have lost its connection
+pub const STALE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const MIN_BACKOFF: Duration = Duration::from_millis(500);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub fn next_backoff(backoff: Duration) -> Duration {
+  (backoff * 2).min(MAX_BACKOFF)
+}
+
+// retries until the REST book is fetched, doubling the delay after every failure
+pub async fn fetch_snapshot_with_backoff(exchange: &Exchange, symbol: &str) -> Option<(OrderBook, i64)> {
+  let mut backoff = MIN_BACKOFF;
+  loop {
+    match fetch_snapshot(exchange, symbol).await {
+      Ok(snapshot) => return snapshot,
+      Err(e) => {
+        error::recover(&format!("snapshot of {} failed, retrying in {:?}", exchange.to_string(), backoff), &e);
+        time::sleep(backoff).await;
+        backoff = next_backoff(backoff);
+      }
+    }
+  }
+}
+
+// a diff is merged into the levels of the venue, a whole book replaces them
+async fn merge(aggregator: &AggregatorHandle, venue: &VenueSync, book: OrderBook) -> Result<(), Error> {
+  if venue.contiguous() {
+    aggregator.update(book).await
+  } else {
+    aggregator.snapshot(book).await
+  }
+}
+
+// sends the sync state of the venue to the aggregator when it changed
+async fn report(
+  aggregator: &AggregatorHandle,
+  exchange: &Exchange,
+  venue: &VenueSync,
+  reported: &mut Option<VenueStatus>) -> Result<(), Error> {
+  let status = venue.status();
+  if reported.as_ref() != Some(&status) {
+    aggregator.status(exchange.to_string(), status.clone()).await?;
+    *reported = Some(status);
+  }
+  Ok(())
+}
+
+// keeps the venue book merged, reconnecting with backoff until the aggregator is gone
 pub async fn connect_exchange(
-  exchange: Exchange, 
-  subscriber : Option<String>, 
-  tx: mpsc::Sender<OrderBook>,
-  aggregator: Arc<RwLock<AggregatedBook>>) -> Result<(), Error> {
-  let exchange_stream = exchange.clone().value();
-  let url = Url::parse(&exchange_stream).expect("bad url string");
+  exchange: Exchange,
+  subscriber : Option<String>,
+  aggregator: AggregatorHandle) -> Result<(), Error> {
+  let url = Url::parse(&exchange.clone().value())?;
+  let symbol = aggregator.query().await?.symbol(&exchange.to_string());
+  let mut venue = VenueSync::new(matches!(exchange, Exchange::Binance(_)));
+  let mut reported = None;
+  let mut backoff = MIN_BACKOFF;
+  loop {
+    if venue.state() == SyncState::Synced {
+      backoff = MIN_BACKOFF;
+    }
+    venue.connecting();
+    report(&aggregator, &exchange, &venue, &mut reported).await?;
+    match stream_book(&exchange, &url, &subscriber, &symbol, &aggregator, &mut venue, &mut reported).await {
+      Ok(()) => println!("{} stream closed, reconnecting in {:?}", exchange.to_string(), backoff),
+      Err(Error::Aggregator(e)) => return Err(e.into()),
+      Err(e) => error::recover(&format!("{} stream failed, reconnecting in {:?}", exchange.to_string(), backoff), &e),
+    }
+    time::sleep(backoff).await;
+    backoff = next_backoff(backoff);
+  }
+}
+
+// one connection to the venue stream, snapshots are fetched on connect,
+// after a gap, after a silence and when the aggregator dropped the venue book
+async fn stream_book(
+  exchange: &Exchange,
+  url: &Url,
+  subscriber: &Option<String>,
+  symbol: &str,
+  aggregator: &AggregatorHandle,
+  venue: &mut VenueSync,
+  reported: &mut Option<VenueStatus>) -> Result<(), Error> {
   println!("connecting to {:?}", url);
-  let (ws_stream, _) = connect_async(url).await?;
+  let (ws_stream, _) = connect_async(url.clone()).await?;
   println!("connected {:?}", exchange);
-  let (mut out_stream, input_stream) = ws_stream.split();
+  let (mut out_stream, mut input_stream) = ws_stream.split();
   if let Some(message) = subscriber {
     println!("subscribing...");
-    match out_stream.send(Message::Text(message))
-    .await {
-      Ok(_) => println!("ok"),
-      Err(e) => println!("{:?}", e)
-    };
-  }  
-  let update_states : Arc<RwLock<i64>> = Arc::new(RwLock::new(match exchange {
-      Exchange::Binance(_) => { aggregator.read().await.last_update_id_binance.to_i64().unwrap() },
-      Exchange::Bitstamp(_) => { aggregator.read().await.last_update_id_bitstamp.to_i64().unwrap() },
-      _ => 0,
-  }));
-  let read_future = input_stream.for_each(|message| async {
-    if let Ok(body) = message {
-      let message_str =  body.to_text().unwrap();
-      if let Ok(order_book) = parse_book(exchange.clone(), message_str, Some(update_states.clone())).await {
-        let _ = tx.send(order_book).await;
+    out_stream.send(Message::Text(message.clone())).await?;
+    println!("ok");
+  }
+  venue.connected();
+  let mut resyncs = aggregator.resyncs();
+  let (snapshot_tx, mut snapshots) = mpsc::channel(1);
+  let mut fetching = false;
+  let mut last_message = Instant::now();
+  let mut resync = true;
+  loop {
+    if resync {
+      venue.resync();
+      if !fetching {
+        fetching = true;
+        let (exchange, symbol, snapshot_tx) = (exchange.clone(), symbol.to_owned(), snapshot_tx.clone());
+        tokio::spawn(async move {
+          let _ = snapshot_tx.send(fetch_snapshot_with_backoff(&exchange, &symbol).await).await;
+        });
       }
     }
-  });
-  read_future.await;
-  Ok::<(), Error>(())
-}
\ No newline at end of file
+    resync = false;
+    report(aggregator, exchange, venue, reported).await?;
+    tokio::select! {
+      message = input_stream.next() => {
+        let body = match message {
+          Some(message) => message?,
+          None => return Ok(()),
+        };
+        last_message = Instant::now();
+        let parsed = match body.to_text() {
+          Ok(text) => parse_book(exchange, text),
+          Err(_) => Err(ParseError::NotText.into()),
+        };
+        let (order_book, sequence) = match parsed {
+          Ok(parsed) => parsed,
+          Err(e) => {
+            error::recover(&format!("skipping {} message", exchange.to_string()), &e);
+            continue;
+          },
+        };
+        match venue.on_message(sequence, order_book) {
+          Outcome::Merge(books) => {
+            for book in books {
+              merge(aggregator, venue, book).await?;
+            }
+          },
+          Outcome::Gap => resync = true,
+          Outcome::Buffered | Outcome::Outdated => {},
+        }
+      },
+      Some(snapshot) = snapshots.recv() => {
+        fetching = false;
+        let (order_book, update_id) = snapshot.unzip();
+        match venue.on_snapshot(update_id) {
+          Outcome::Merge(books) => {
+            if let Some(order_book) = order_book {
+              aggregator.snapshot(order_book).await?;
+            }
+            for book in books {
+              merge(aggregator, venue, book).await?;
+            }
+          },
+          // the snapshot is older than the buffered updates, fetch a newer one
+          _ => resync = true,
+        }
+      },
+      Ok(exchange_name) = resyncs.recv() => {
+        resync = exchange_name == exchange.to_string();
+      },
+      _ = time::sleep_until(last_message + STALE_TIMEOUT) => {
+        println!("no update from {} for {:?}, resyncing", exchange.to_string(), STALE_TIMEOUT);
+        last_message = Instant::now();
+        resync = true;
+      },
+    }
+  }
+}
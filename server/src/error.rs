@@ -0,0 +1,282 @@
+use rust_decimal::Decimal;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio_tungstenite::tungstenite;
+
+// failures talking to a venue, over its stream or its REST api. The large
+// errors are boxed to keep results small
+#[derive(Debug)]
+pub enum ConnectorError {
+  Connection(Box<tungstenite::Error>),
+  BadUrl(url::ParseError),
+  Http(Box<reqwest::Error>),
+  UnknownSymbol(String),
+}
+
+// a venue message that can not be merged, only that message is lost
+#[derive(Debug)]
+pub enum ParseError {
+  BadJson(serde_json::Error),
+  NotText,
+  // update id that is no integer
+  BadSequence(Decimal),
+  // venue and trade time of a trade older than the released trades
+  LateTrade(String, i64),
+}
+
+#[derive(Debug)]
+pub enum AggregatorError {
+  // the task owning the book is gone
+  Stopped,
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+  Transport(tonic::transport::Error),
+  BadAddr(std::net::AddrParseError),
+  Io(std::io::Error),
+  Join(tokio::task::JoinError),
+  BadConfig(String),
+  // a decimal of a reply that can not be sent as a double
+  NoDouble(Decimal),
+}
+
+#[derive(Debug)]
+pub enum Error {
+  Connector(ConnectorError),
+  Parser(ParseError),
+  Aggregator(AggregatorError),
+  Server(ServerError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+  Connector,
+  Parser,
+  Aggregator,
+  Server,
+}
+
+impl Subsystem {
+  pub const ALL: [Subsystem; 4] = [Self::Connector, Self::Parser, Self::Aggregator, Self::Server];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Connector => "connector",
+      Self::Parser => "parser",
+      Self::Aggregator => "aggregator",
+      Self::Server => "server",
+    }
+  }
+}
+
+impl Error {
+  pub fn subsystem(&self) -> Subsystem {
+    match self {
+      Self::Connector(_) => Subsystem::Connector,
+      Self::Parser(_) => Subsystem::Parser,
+      Self::Aggregator(_) => Subsystem::Aggregator,
+      Self::Server(_) => Subsystem::Server,
+    }
+  }
+}
+
+impl fmt::Display for ConnectorError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Connection(e) => write!(f, "stream failed: {}", e),
+      Self::BadUrl(e) => write!(f, "bad url: {}", e),
+      Self::Http(e) => write!(f, "request failed: {}", e),
+      Self::UnknownSymbol(symbol) => write!(f, "unknown symbol {}", symbol),
+    }
+  }
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::BadJson(e) => write!(f, "bad json: {}", e),
+      Self::NotText => write!(f, "message is no text"),
+      Self::BadSequence(id) => write!(f, "bad update id {}", id),
+      Self::LateTrade(exchange, time) => write!(f, "{} trade of {} is older than the released trades", exchange, time),
+    }
+  }
+}
+
+impl fmt::Display for AggregatorError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Stopped => write!(f, "aggregator stopped"),
+    }
+  }
+}
+
+impl fmt::Display for ServerError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Transport(e) => write!(f, "transport failed: {}", e),
+      Self::BadAddr(e) => write!(f, "bad address: {}", e),
+      Self::Io(e) => write!(f, "io failed: {}", e),
+      Self::Join(e) => write!(f, "task failed: {}", e),
+      Self::BadConfig(reason) => write!(f, "bad config: {}", reason),
+      Self::NoDouble(value) => write!(f, "{} has no double", value),
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Connector(e) => write!(f, "connector: {}", e),
+      Self::Parser(e) => write!(f, "parser: {}", e),
+      Self::Aggregator(e) => write!(f, "aggregator: {}", e),
+      Self::Server(e) => write!(f, "server: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for ConnectorError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Connection(e) => Some(e.as_ref()),
+      Self::BadUrl(e) => Some(e),
+      Self::Http(e) => Some(e.as_ref()),
+      Self::UnknownSymbol(_) => None,
+    }
+  }
+}
+
+impl std::error::Error for ParseError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::BadJson(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl std::error::Error for AggregatorError {}
+
+impl std::error::Error for ServerError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Transport(e) => Some(e),
+      Self::BadAddr(e) => Some(e),
+      Self::Io(e) => Some(e),
+      Self::Join(e) => Some(e),
+      Self::BadConfig(_) | Self::NoDouble(_) => None,
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Connector(e) => Some(e),
+      Self::Parser(e) => Some(e),
+      Self::Aggregator(e) => Some(e),
+      Self::Server(e) => Some(e),
+    }
+  }
+}
+
+impl From<ConnectorError> for Error {
+  fn from(e: ConnectorError) -> Self {
+    Self::Connector(e)
+  }
+}
+
+impl From<ParseError> for Error {
+  fn from(e: ParseError) -> Self {
+    Self::Parser(e)
+  }
+}
+
+impl From<AggregatorError> for Error {
+  fn from(e: AggregatorError) -> Self {
+    Self::Aggregator(e)
+  }
+}
+
+impl From<ServerError> for Error {
+  fn from(e: ServerError) -> Self {
+    Self::Server(e)
+  }
+}
+
+impl From<tungstenite::Error> for Error {
+  fn from(e: tungstenite::Error) -> Self {
+    Self::Connector(ConnectorError::Connection(Box::new(e)))
+  }
+}
+
+impl From<url::ParseError> for Error {
+  fn from(e: url::ParseError) -> Self {
+    Self::Connector(ConnectorError::BadUrl(e))
+  }
+}
+
+impl From<reqwest::Error> for Error {
+  fn from(e: reqwest::Error) -> Self {
+    Self::Connector(ConnectorError::Http(Box::new(e)))
+  }
+}
+
+impl From<serde_json::Error> for Error {
+  fn from(e: serde_json::Error) -> Self {
+    Self::Parser(ParseError::BadJson(e))
+  }
+}
+
+impl From<tonic::transport::Error> for Error {
+  fn from(e: tonic::transport::Error) -> Self {
+    Self::Server(ServerError::Transport(e))
+  }
+}
+
+impl From<std::net::AddrParseError> for Error {
+  fn from(e: std::net::AddrParseError) -> Self {
+    Self::Server(ServerError::BadAddr(e))
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(e: std::io::Error) -> Self {
+    Self::Server(ServerError::Io(e))
+  }
+}
+
+impl From<tokio::task::JoinError> for Error {
+  fn from(e: tokio::task::JoinError) -> Self {
+    Self::Server(ServerError::Join(e))
+  }
+}
+
+static RECOVERED: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+// logs an error the task carries on after and counts it for its subsystem
+pub fn recover(context: &str, error: &Error) {
+  RECOVERED[error.subsystem() as usize].fetch_add(1, Ordering::Relaxed);
+  println!("{}: {}", context, error);
+}
+
+// errors recovered from since start
+pub fn recovered(subsystem: Subsystem) -> u64 {
+  RECOVERED[subsystem as usize].load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+pub mod test {
+  use super::{recover, recovered, Error, ParseError, Subsystem};
+
+  #[test]
+  fn recovered_errors() {
+    let before = recovered(Subsystem::Parser);
+    let error: Error = serde_json::from_str::<u32>("{").unwrap_err().into();
+    assert_eq!(error.subsystem(), Subsystem::Parser);
+    assert!(error.to_string().starts_with("parser: bad json"));
+    recover("test message", &error);
+    recover("test message", &ParseError::NotText.into());
+    assert!(recovered(Subsystem::Parser) >= before + 2);
+  }
+}
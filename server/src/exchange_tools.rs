@@ -1,33 +1,50 @@
-use crate::client::error::Error;
+use crate::alerts::{AlertKind, Crossing, Tops};
+use crate::error::{Error, ParseError};
+use crate::fees::{self, FeeSchedules};
+use crate::instruments::{self, Instrument};
+use crate::metrics::{self, BookMetrics, MetricsConfig};
+use crate::router::{round_down, round_up};
+use crate::sync::{Sequence, SyncState, VenueStatus};
+use chrono::Utc;
+use itertools::Itertools;
 use num_traits::cast::ToPrimitive;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use serde::{Serialize, Deserialize};
-use std::collections::{BTreeMap, HashMap};
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::OnceLock;
+use std::time::Duration;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
-use tokio::sync::RwLock;
 
 pub const BITSTAMP_WSS: &str = "wss://ws.bitstamp.net";
-pub const BINANCE_WSS: &str = "wss://stream.binance.com:9443/ws/{}@depth10@100ms";
+pub const BINANCE_WSS: &str = "wss://stream.binance.com:9443/ws/{}@depth@100ms";
 
 pub const BITSTAMP_API: &str = "https://www.bitstamp.net/api/v2/order_book/{}/";
 pub const BINANCE_API: &str = "https://api.binance.com/api/v3/depth?symbol={}&limit=100";
+// a hung REST call of a venue fails after this long
+pub const REST_TIMEOUT: Duration = Duration::from_secs(10);
+// smaller buckets overflow the rounding of the prices
+pub const MIN_BUCKET: Decimal = dec!(0.000000000001);
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct OrderBook {
   pub exchange: String,
   pub bids: Vec<Level>,
   pub asks: Vec<Level>,
+  // where the book stands in the venue stream, zero when unknown
+  #[serde(default)]
+  pub update: VenueUpdate,
 }
 
+// the REST books carry no event time, bitstamp books are ordered by microtimestamp
 impl From<binance::OrderBook> for OrderBook {
   fn from(ob: binance::OrderBook) -> Self {
     Self {
       exchange: String::from("binance"),
       asks: ob.asks.into_iter().map(|(price, amount)| Level { exchange: String::from("binance"), price, amount}).collect(),
       bids: ob.bids.into_iter().map(|(price, amount)| Level { exchange: String::from("binance"), price, amount}).collect(),
+      update: VenueUpdate::received(ob.lastUpdateId.to_i64().unwrap_or_default(), 0),
     }
   }
 }
@@ -38,10 +55,40 @@ impl From<bitstamp::OrderBook> for OrderBook {
       exchange: String::from("bitstamp"),
       asks: ob.asks.into_iter().map(|(price, amount)| Level { exchange: String::from("bitstamp"), price, amount}).collect(),
       bids: ob.bids.into_iter().map(|(price, amount)| Level { exchange: String::from("bitstamp"), price, amount}).collect(),
+      update: VenueUpdate::received(ob.microtimestamp.to_i64().unwrap_or_default(), micros_to_millis(ob.microtimestamp)),
     }
   }
 }
 
+// last merged update of a venue, times are milliseconds since the epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VenueUpdate {
+  // binance update id, bitstamp microtimestamp
+  pub update_id: i64,
+  // event time set by the venue
+  pub exchange_time: i64,
+  pub receive_time: i64,
+}
+
+impl VenueUpdate {
+  pub fn received(update_id: i64, exchange_time: i64) -> Self {
+    Self { update_id, exchange_time, receive_time: Utc::now().timestamp_millis() }
+  }
+}
+
+// a venue as a summary reports it, the state is None for venues without a connector
+#[derive(Debug, Clone, PartialEq)]
+pub struct VenueReport {
+  pub update: VenueUpdate,
+  pub state: Option<SyncState>,
+  // the venue has levels in the merged book
+  pub included: bool,
+}
+
+fn micros_to_millis(micros: Decimal) -> i64 {
+  (micros / Decimal::from(1000)).to_i64().unwrap_or_default()
+}
+
 #[derive(Debug, Clone)]
 pub enum Exchange {
   Bitstamp(String),
@@ -56,39 +103,113 @@ pub struct Level {
   pub amount: Decimal,
 }
 
-#[derive(Debug, EnumIter, Clone, Copy)]
+#[derive(Debug, EnumIter, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OrderSide {
   Ask,
   Bid,
 }
 
-#[derive(Debug)]
+// side of an incoming order, a buy takes liquidity from the asks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+  Buy,
+  Sell,
+}
+
+impl Side {
+  pub fn book_side(self) -> OrderSide {
+    match self {
+      Self::Buy => OrderSide::Ask,
+      Self::Sell => OrderSide::Bid,
+    }
+  }
+}
+
+// total amount at a price with the amount of every exchange
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsolidatedLevel {
+  pub price: Decimal,
+  pub amount: Decimal,
+  pub venues: BTreeMap<String, Decimal>,
+}
+
+impl ConsolidatedLevel {
+  fn new(levels: Vec<Level>) -> Self {
+    Self {
+      price: levels[0].price,
+      amount: levels.iter().map(|l| l.amount).sum(),
+      venues: levels.into_iter().map(|l| (l.exchange, l.amount)).collect(),
+    }
+  }
+}
+
+// consolidated levels are only filled by get_consolidated_levels
+#[derive(Debug, Clone)]
 pub struct Summary {
   pub asks: Vec<Level>,
   pub bids: Vec<Level>,
   pub spread: Decimal,
+  pub metrics: BookMetrics,
+  pub consolidated_asks: Vec<ConsolidatedLevel>,
+  pub consolidated_bids: Vec<ConsolidatedLevel>,
+  // of the merged book, see AggregatedBook
+  pub sequence: u64,
+  // when the snapshot was published, zero for books built on request
+  pub publish_time: i64,
+  pub venues: BTreeMap<String, VenueReport>,
 }
 
+#[derive(Clone)]
 pub struct AggregatedBook {
   pub currency_pair: String,
-  pub last_update_id_binance: Decimal,
-  pub last_update_id_bitstamp: Decimal,
-  pub update_counter: i64,
   pub asks: BTreeMap<Decimal, HashMap<String, Decimal>>,
   pub bids: BTreeMap<Decimal, HashMap<String, Decimal>>,
   pub spread: Decimal,
+  pub metrics_config: MetricsConfig,
+  pub metrics: BookMetrics,
+  // per exchange metadata, venue prices are merged as they are and bucketed
+  // onto tick_size when published, see on_tick
+  pub instruments: HashMap<String, Instrument>,
+  pub tick_size: Decimal,
+  // crossing between venues, kept to report when it starts and ends
+  pub market_crossing: Option<Crossing>,
+  // time of the last merged book per exchange, milliseconds since the epoch
+  pub last_updates: HashMap<String, i64>,
+  // sync state of the stream of every exchange, reported by its connector
+  pub venues: HashMap<String, VenueStatus>,
+  pub venue_updates: HashMap<String, VenueUpdate>,
+  // number of merged venue books, a jump tells a reader it skipped books
+  pub sequence: u64,
 }
 
 impl AggregatedBook {
   pub fn new(currency_pair: String) -> Self {
     Self {
       currency_pair,
-      last_update_id_binance: dec!(0),
-      last_update_id_bitstamp: dec!(0),
-      update_counter: 0,
       asks: BTreeMap::new(),
       bids: BTreeMap::new(),
       spread: dec!(0),
+      metrics_config: MetricsConfig::default(),
+      metrics: BookMetrics::default(),
+      instruments: HashMap::new(),
+      tick_size: dec!(0),
+      market_crossing: None,
+      last_updates: HashMap::new(),
+      venues: HashMap::new(),
+      venue_updates: HashMap::new(),
+      sequence: 0,
+    }
+  }
+
+  pub fn set_instruments(&mut self, instruments: Vec<Instrument>) {
+    self.tick_size = instruments::common_tick(&instruments);
+    self.instruments = instruments.into_iter().map(|i| (i.exchange.clone(), i)).collect();
+  }
+
+  pub fn symbol(&self, exchange: &str) -> String {
+    match self.instruments.get(exchange) {
+      Some(instrument) => instrument.symbol.clone(),
+      None => Instrument::new(exchange, &self.currency_pair).symbol,
     }
   }
 
@@ -101,17 +222,192 @@ impl AggregatedBook {
     Box::new(levels.iter()
     .map(move |val| val.1.iter()
     .map(|val2| Level { exchange: val2.0.clone(), price: *val.0, amount: *val2.1 })
-    .filter(|val| val.amount.to_f64().unwrap() > 0.0).collect::<Vec<Level>>()))
+    .filter(|val| val.amount > dec!(0)).collect::<Vec<Level>>()))
   }
 
   pub fn get_levels(&self, level_num: usize) -> Summary {
-    let bids: Vec<_> = self.dump_levels(OrderSide::Bid).rev().take(level_num)
+    let bids: Vec<_> = self.dump_levels(OrderSide::Bid).rev().filter(|l| !l.is_empty()).take(level_num)
     .flatten().collect();
-    let asks: Vec<_> = self.dump_levels(OrderSide::Ask).take(level_num)
+    let asks: Vec<_> = self.dump_levels(OrderSide::Ask).filter(|l| !l.is_empty()).take(level_num)
     .flatten().collect();
-    Summary { asks, bids, spread: self.spread }
-  } 
+    let mut summary = Summary {
+      asks,
+      bids,
+      spread: self.spread,
+      metrics: self.metrics.clone(),
+      consolidated_asks: Vec::new(),
+      consolidated_bids: Vec::new(),
+      sequence: 0,
+      publish_time: 0,
+      venues: BTreeMap::new(),
+    };
+    self.stamp(&mut summary);
+    summary
+  }
+
+  // sequence and venues of this book on a summary, also of a derived book
+  pub fn stamp(&self, summary: &mut Summary) {
+    summary.sequence = self.sequence;
+    let with_levels = self.exchanges();
+    let exchanges: BTreeSet<&String> = self.venue_updates.keys().chain(self.venues.keys()).collect();
+    summary.venues = exchanges.into_iter()
+      .map(|exchange| {
+        let state = self.venues.get(exchange).map(|status| status.state);
+        // levels of a venue that is not synced are stale
+        let included = with_levels.contains(exchange) && matches!(state, None | Some(SyncState::Synced));
+        (exchange.clone(), VenueReport {
+          update: self.venue_updates.get(exchange).copied().unwrap_or_default(),
+          state,
+          included,
+        })
+      })
+      .collect();
+  }
+
+  pub fn get_consolidated_levels(&self, level_num: usize) -> Summary {
+    let mut summary = self.get_levels(level_num);
+    summary.consolidated_bids = self.dump_levels(OrderSide::Bid).rev()
+      .filter(|l| !l.is_empty()).take(level_num).map(ConsolidatedLevel::new).collect();
+    summary.consolidated_asks = self.dump_levels(OrderSide::Ask)
+      .filter(|l| !l.is_empty()).take(level_num).map(ConsolidatedLevel::new).collect();
+    summary
+  }
+
+  // levels of a single exchange ordered from the best price outwards
+  pub fn venue_levels(&self, side: OrderSide, exchange: &str) -> Vec<(Decimal, Decimal)> {
+    let levels = self.dump_levels(side)
+      .flatten()
+      .filter(|l| l.exchange == exchange)
+      .map(|l| (l.price, l.amount));
+    match side {
+      OrderSide::Ask => levels.collect(),
+      OrderSide::Bid => levels.rev().collect(),
+    }
+  }
+
+  // venues with a level of a non-zero amount
+  pub fn exchanges(&self) -> Vec<String> {
+    self.asks.values().chain(self.bids.values())
+      .flat_map(|amounts| amounts.iter().filter(|(_, amount)| !amount.is_zero()).map(|(exchange, _)| exchange.clone()))
+      .sorted()
+      .dedup()
+      .collect()
+  }
+
+  // total amount over all exchanges per price, from the best price outwards
+  pub fn price_levels(&self, side: OrderSide) -> Vec<(Decimal, Decimal)> {
+    let levels = self.dump_levels(side)
+      .filter(|l| !l.is_empty())
+      .map(|l| (l[0].price, l.iter().map(|l| l.amount).sum()));
+    match side {
+      OrderSide::Ask => levels.collect(),
+      OrderSide::Bid => levels.rev().collect(),
+    }
+  }
+
+  // copy of the book with every price adjusted by the venue taker fee,
+  // so the best level is the one that is actually cheapest to trade against
+  pub fn fee_adjusted(&self, fees: &FeeSchedules) -> AggregatedBook {
+    let mut adjusted = AggregatedBook::new(self.currency_pair.clone());
+    adjusted.metrics_config = self.metrics_config.clone();
+    for side in OrderSide::iter() {
+      for level in self.dump_levels(side).flatten() {
+        let fee = fees::taker_fee(fees, &level.exchange);
+        let price = fees::effective_price(side, level.price, fee);
+        adjusted.insert_level(side, Level { price, ..level });
+      }
+    }
+    if let (Some(ask), Some(bid)) = (adjusted.asks.first_key_value(), adjusted.bids.last_key_value()) {
+      adjusted.spread = ask.0 - bid.0;
+    }
+    adjusted.metrics = metrics::compute(&adjusted, &adjusted.metrics_config);
+    adjusted
+  }
+
+  // copy of the book with the levels grouped into price buckets, bids round
+  // down and asks round up, the amounts of an exchange are summed per bucket.
+  // Spread and metrics stay those of the exact book.
+  pub fn bucketed(&self, bucket: Decimal) -> AggregatedBook {
+    let mut bucketed = AggregatedBook::new(self.currency_pair.clone());
+    self.bucket_levels(bucket, &mut bucketed);
+    bucketed.spread = self.spread;
+    bucketed.metrics = self.metrics.clone();
+    bucketed
+  }
+
+  fn bucket_levels(&self, bucket: Decimal, bucketed: &mut AggregatedBook) {
+    for side in OrderSide::iter() {
+      for level in self.dump_levels(side).flatten() {
+        let (storage, price) = match side {
+          OrderSide::Ask => (&mut bucketed.asks, round_up(level.price, bucket)),
+          OrderSide::Bid => (&mut bucketed.bids, round_down(level.price, bucket)),
+        };
+        *storage.entry(price.normalize()).or_default().entry(level.exchange).or_default() += level.amount;
+      }
+    }
+  }
+
+  // the book with the prices of every venue bucketed onto the common tick, bids
+  // down and asks up so a bucket never looks better than the prices it holds.
+  // The venue books stay at their own prices, a diff only touches its prices then
+  pub fn on_tick(self) -> AggregatedBook {
+    if self.tick_size.is_zero() {
+      return self;
+    }
+    let mut bucketed = AggregatedBook { asks: BTreeMap::new(), bids: BTreeMap::new(), ..self.clone() };
+    self.bucket_levels(self.tick_size, &mut bucketed);
+    if let (Some(ask), Some(bid)) = (bucketed.asks.first_key_value(), bucketed.bids.last_key_value()) {
+      bucketed.spread = ask.0 - bid.0;
+    }
+    bucketed
+  }
 
+  // copy of the book priced in another quote currency, `rate` being the
+  // price of the current quote currency in the new one
+  pub fn converted(&self, rate: Decimal) -> AggregatedBook {
+    let mut converted = AggregatedBook::new(self.currency_pair.clone());
+    for side in OrderSide::iter() {
+      for level in self.dump_levels(side).flatten() {
+        converted.insert_level(side, Level { price: level.price * rate, ..level });
+      }
+    }
+    converted.spread = self.spread * rate;
+    converted.metrics_config = self.metrics_config.clone();
+    converted.metrics = self.metrics.converted(rate);
+    converted
+  }
+
+  // copy of the book with the best `levels` prices per side, the copy costs
+  // the same however deep the book is
+  pub fn top(&self, levels: usize) -> AggregatedBook {
+    AggregatedBook {
+      currency_pair: self.currency_pair.clone(),
+      asks: self.asks.iter().take(levels).map(|(price, amounts)| (*price, amounts.clone())).collect(),
+      bids: self.bids.iter().rev().take(levels).map(|(price, amounts)| (*price, amounts.clone())).collect(),
+      spread: self.spread,
+      metrics_config: self.metrics_config.clone(),
+      metrics: self.metrics.clone(),
+      instruments: self.instruments.clone(),
+      tick_size: self.tick_size,
+      market_crossing: self.market_crossing.clone(),
+      last_updates: self.last_updates.clone(),
+      venues: self.venues.clone(),
+      venue_updates: self.venue_updates.clone(),
+      sequence: self.sequence,
+    }
+  }
+
+  // no bucket is finer than the common tick of the venues
+  pub fn min_bucket(&self) -> Decimal {
+    self.tick_size.max(MIN_BUCKET)
+  }
+
+  // bucket size of `bps` basis points of the mid price
+  pub fn bps_bucket(&self, bps: Decimal) -> Decimal {
+    self.metrics.mid * bps / dec!(10000)
+  }
+
+  // a zero amount removes the level of the venue, as in a binance diff
   pub fn insert_level(&mut self, order_side: OrderSide, level: Level) {
     let storage = match order_side {
         OrderSide::Ask => {
@@ -121,44 +417,98 @@ impl AggregatedBook {
           &mut self.bids
         }
     };
-    if !storage.contains_key(&level.price) {
-      storage.insert(level.price, HashMap::new());
-    }
-    if let Some(amount_map)= storage.get_mut(&level.price) {
-      amount_map.insert(level.exchange, level.amount);
+    if level.amount.is_zero() {
+      if let Some(amount_map) = storage.get_mut(&level.price) {
+        amount_map.remove(&level.exchange);
+        if amount_map.is_empty() {
+          storage.remove(&level.price);
+        }
+      }
+      return;
     }
+    storage.entry(level.price).or_default().insert(level.exchange, level.amount);
   }
-  pub async fn update(&mut self, orderbook: OrderBook) {
-    let mut ob_vec = vec![orderbook];
-    if self.update_counter > 1000 || self.update_counter == 0 {
-      let body = reqwest::get(BINANCE_API.replace("{}", &self.currency_pair.to_ascii_uppercase()))
-      .await.unwrap().text().await.unwrap();
-      println!("updating snapshort binance...");
-      let order_book_binance: binance::OrderBook = serde_json::from_str(&body).unwrap();
-      let body = reqwest::get(BITSTAMP_API.replace("{}", &self.currency_pair))
-      .await.unwrap().text().await.unwrap();
-      println!("updating snapshort bitstamp...");
-      let order_book_bitstamp: bitstamp::OrderBook = serde_json::from_str(&body).unwrap();
-      self.update_counter = 1;
-      self.last_update_id_binance = order_book_binance.lastUpdateId;
-      self.last_update_id_bitstamp = order_book_bitstamp.timestamp;
-      ob_vec = vec![order_book_binance.into(), order_book_bitstamp.into()];
-      self.asks.clear();
-      self.bids.clear();
+
+  fn remove_venue(&mut self, exchange: &str) {
+    for storage in [&mut self.asks, &mut self.bids] {
+      for amounts in storage.values_mut() {
+        amounts.remove(exchange);
+      }
+      storage.retain(|_, amounts| !amounts.is_empty());
     }
-    for ob in ob_vec {
-      for side in OrderSide::iter() { 
-        let storage = match side {
-          OrderSide::Ask => &ob.asks,
-          OrderSide::Bid => &ob.bids
-        };
-        for level in storage.into_iter() {
-          self.insert_level(side, level.clone());
-        }
+  }
+
+  // replaces every level of the venue with a whole book, its REST book or a
+  // streamed one, so levels the venue no longer sends are gone
+  pub fn apply_snapshot(&mut self, orderbook: OrderBook) -> Vec<AlertKind> {
+    self.remove_venue(&orderbook.exchange);
+    self.update(orderbook)
+  }
+
+  // merges a venue book and returns the crossings it caused, a venue whose
+  // merged levels cross in themselves is dropped and has to be resynced then
+  pub fn update(&mut self, ob: OrderBook) -> Vec<AlertKind> {
+    let mut events = Vec::new();
+    self.last_updates.insert(ob.exchange.clone(), Utc::now().timestamp_millis());
+    self.venue_updates.insert(ob.exchange.clone(), ob.update);
+    self.sequence += 1;
+    for side in OrderSide::iter() { 
+      let storage = match side {
+        OrderSide::Ask => &ob.asks,
+        OrderSide::Bid => &ob.bids
+      };
+      for level in storage.iter() {
+        self.insert_level(side, level.clone());
       }
     }
-    self.spread = self.asks.first_key_value().unwrap().0 - self.bids.last_key_value().unwrap().0;
-    self.update_counter += 1;
+    let mut tops = Tops::of(self);
+    for crossing in tops.venue_crossings() {
+      println!("dropping crossed {} book, bid {} ask {}", crossing.bid_exchange, crossing.best_bid, crossing.best_ask);
+      self.remove_venue(&crossing.bid_exchange);
+      tops.remove(&crossing.bid_exchange);
+      events.push(AlertKind::VenueCrossed(crossing));
+    }
+    if let (Some(ask), Some(bid)) = (self.asks.first_key_value(), self.bids.last_key_value()) {
+      self.spread = ask.0 - bid.0;
+    }
+    self.metrics = metrics::compute(self, &self.metrics_config);
+    match (tops.market_crossing(), self.market_crossing.take()) {
+      (Some(crossing), None) => {
+        events.push(AlertKind::MarketCrossed(crossing.clone()));
+        self.market_crossing = Some(crossing);
+      },
+      (None, Some(previous)) => events.push(AlertKind::MarketUncrossed(previous)),
+      (current, _) => self.market_crossing = current,
+    }
+    events
+  }
+}
+
+// shared by every REST call to the venues
+pub fn rest_client() -> &'static reqwest::Client {
+  static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+  CLIENT.get_or_init(|| reqwest::Client::builder().timeout(REST_TIMEOUT).build().unwrap_or_default())
+}
+
+// REST book of a venue with the update id its stream continues from,
+// None for venues without a REST api
+pub async fn fetch_snapshot(exchange: &Exchange, symbol: &str) -> Result<Option<(OrderBook, i64)>, Error> {
+  match exchange {
+    Exchange::Binance(_) => {
+      let body = rest_client().get(BINANCE_API.replace("{}", symbol)).send().await?.text().await?;
+      println!("updating snapshort binance...");
+      let order_book: binance::OrderBook = serde_json::from_str(&body)?;
+      let id = sequence_id(order_book.lastUpdateId)?;
+      Ok(Some((order_book.into(), id)))
+    },
+    Exchange::Bitstamp(_) => {
+      let body = rest_client().get(BITSTAMP_API.replace("{}", symbol)).send().await?.text().await?;
+      println!("updating snapshort bitstamp...");
+      let order_book: bitstamp::OrderBook = serde_json::from_str(&body)?;
+      let id = sequence_id(order_book.microtimestamp)?;
+      Ok(Some((order_book.into(), id)))
+    },
+    Exchange::Other(_) => Ok(None),
   }
 }
 
@@ -184,80 +534,54 @@ impl ToString for Exchange {
 
 use crate::{binance, bitstamp};
 
-async fn sync_update(prev_update_state: Option<Arc<RwLock<i64>>>, current_state : Decimal) -> bool {
-  let mut update_is_ok = false;
-  if let Some(prev_state) = prev_update_state {
-    let update_state = current_state.to_i64().unwrap();
-    if update_state >= *prev_state.read().await + 1 {
-      *prev_state.write().await = update_state;
-      update_is_ok = true;
-    }
-  } else {
-    update_is_ok = true;
-  } 
-  update_is_ok
+fn sequence_id(id: Decimal) -> Result<i64, Error> {
+  id.to_i64().ok_or_else(|| ParseError::BadSequence(id).into())
+}
+
+fn levels(exchange: &str, levels: Vec<(Decimal, Decimal)>) -> Vec<Level> {
+  levels.into_iter().map(|(price, amount)| Level { exchange: exchange.to_owned(), price, amount }).collect()
 }
 
-pub async fn parse_book(exchange: Exchange, message: &str, prev_update_state: Option<Arc<RwLock<i64>>>) -> Result<OrderBook, Error> {
-  //println!("received message: {:?}", message);
+// a venue message with the update ids it covers, binance sends diffs and
+// bitstamp whole books ordered by their microtimestamp
+pub fn parse_book(exchange: &Exchange, message: &str) -> Result<(OrderBook, Option<Sequence>), Error> {
   match exchange {
     Exchange::Bitstamp(_) => {
-      let order_book: Result<bitstamp::Event,_> = serde_json::from_str(message);
-      match order_book {
-        Ok(val) => { 
-          if sync_update(prev_update_state, val.data.timestamp).await {
-            return Ok(OrderBook {
-              exchange: String::from("bitstamp"),
-              asks: val.data.asks.into_iter().map(|(price, amount)| Level { exchange: String::from("bitstamp"), price, amount}).collect(),
-              bids: val.data.bids.into_iter().map(|(price, amount)| Level { exchange: String::from("bitstamp"), price, amount}).collect(),
-            });
-          }
-          Err(Error::OutdatedUpdate())
-        },
-        Err(e) => { 
-          Err(e.into())
-        }
-      }
+      let val: bitstamp::Event = serde_json::from_str(message)?;
+      let id = sequence_id(val.data.microtimestamp)?;
+      Ok((OrderBook {
+        exchange: String::from("bitstamp"),
+        asks: levels("bitstamp", val.data.asks),
+        bids: levels("bitstamp", val.data.bids),
+        update: VenueUpdate::received(id, micros_to_millis(val.data.microtimestamp)),
+      }, Some(Sequence::new(id, id))))
     },
     Exchange::Binance(_) => {
-      let order_book: Result<binance::OrderBook, _> = serde_json::from_str(message);
-      match order_book {
-        Ok(val) => { 
-          if sync_update(prev_update_state, val.lastUpdateId).await {
-            return Ok(OrderBook {
-              exchange: String::from("binance"),
-              asks: val.asks.into_iter().map(|(price, amount)| Level { exchange: String::from("binance"), price, amount}).collect(),
-              bids: val.bids.into_iter().map(|(price, amount)| Level { exchange: String::from("binance"), price, amount}).collect(),
-            });
-          }
-          Err(Error::OutdatedUpdate())
-        },
-        Err(e) =>  { 
-          Err(e.into())
-        },
-      }
+      let val: binance::DepthUpdate = serde_json::from_str(message)?;
+      Ok((OrderBook {
+        exchange: String::from("binance"),
+        asks: levels("binance", val.a),
+        bids: levels("binance", val.b),
+        update: VenueUpdate::received(val.u, val.E),
+      }, Some(Sequence::new(val.U, val.u))))
     },
     Exchange::Other(_) => {
-      let order_book: Result<binance::OrderBook, _> = serde_json::from_str(message);
-      match order_book {
-        Ok(val) => { 
-          Ok(OrderBook {
-            exchange: String::from("other"),
-            asks: val.asks.into_iter().map(|(price, amount)| Level { exchange: String::from("other"), price, amount}).collect(),
-            bids: val.bids.into_iter().map(|(price, amount)| Level { exchange: String::from("other"), price, amount}).collect(),
-          })
-        },
-        Err(e) =>  { 
-          Err(e.into())
-        },
-      }
+      let val: binance::OrderBook = serde_json::from_str(message)?;
+      Ok((OrderBook {
+        exchange: String::from("other"),
+        asks: levels("other", val.asks),
+        bids: levels("other", val.bids),
+        update: VenueUpdate::received(val.lastUpdateId.to_i64().unwrap_or_default(), 0),
+      }, None))
     }
   }
 }
 
 #[cfg(test)]
 pub mod test {
-  use crate::exchange_tools::Level;
+  use crate::exchange_tools::{AggregatedBook, Exchange, Level, OrderBook, OrderSide, parse_book, VenueUpdate};
+  use crate::fees::{FeeSchedule, FeeSchedules};
+  use crate::sync::{SyncState, VenueStatus};
   use num_traits::cast::ToPrimitive;
   use rust_decimal::Decimal;
   use rust_decimal_macros::dec;
@@ -277,4 +601,124 @@ fn collect_echanges() {
     .filter(|val| val.amount.to_f64().unwrap() > 0.0).collect()).take(3).collect();
     assert!(non_flattened.len() == 3);
   } 
-}
\ No newline at end of file
+
+#[test]
+fn fee_adjusted_book() {
+  let mut book = AggregatedBook::new(String::from("ethbtc"));
+  book.insert_level(OrderSide::Ask, Level { exchange: String::from("binance"), price: dec!(100), amount: dec!(1) });
+  book.insert_level(OrderSide::Ask, Level { exchange: String::from("bitstamp"), price: dec!(100.1), amount: dec!(1) });
+  book.insert_level(OrderSide::Bid, Level { exchange: String::from("binance"), price: dec!(99), amount: dec!(1) });
+  let fees = FeeSchedules::from([
+    (String::from("binance"), FeeSchedule { maker: dec!(0), taker: dec!(0.002) }),
+    (String::from("bitstamp"), FeeSchedule { maker: dec!(0), taker: dec!(0) })]);
+  let summary = book.fee_adjusted(&fees).get_levels(10);
+  assert_eq!(summary.asks[0].exchange, "bitstamp");
+  assert_eq!(summary.asks[1].price, dec!(100.2));
+  assert_eq!(summary.bids[0].price, dec!(98.802));
+  assert_eq!(summary.spread, dec!(1.298));
+  }
+
+#[test]
+fn bucketed_levels() {
+  let mut book = AggregatedBook::new(String::from("ethbtc"));
+  for (side, exchange, price, amount) in [
+    (OrderSide::Ask, "binance", dec!(0.071012), dec!(1)),
+    (OrderSide::Ask, "binance", dec!(0.071018), dec!(2)),
+    (OrderSide::Ask, "bitstamp", dec!(0.071019), dec!(4)),
+    (OrderSide::Ask, "bitstamp", dec!(0.071021), dec!(8)),
+    (OrderSide::Bid, "binance", dec!(0.070995), dec!(1)),
+    (OrderSide::Bid, "bitstamp", dec!(0.070991), dec!(1)),
+  ] {
+    book.insert_level(side, Level { exchange: exchange.to_owned(), price, amount });
+  }
+  assert!(book.min_bucket() > dec!(0));
+  // a price without amounts is no level
+  book.asks.insert(dec!(0.07101), HashMap::new());
+  let summary = book.bucketed(dec!(0.00001)).get_levels(1);
+  assert_eq!(book.get_levels(1).asks[0].price, dec!(0.071012));
+  assert_eq!(summary.asks.len(), 2);
+  assert!(summary.asks.iter().all(|l| l.price == dec!(0.07102)));
+  let binance: Vec<_> = summary.asks.iter().filter(|l| l.exchange == "binance").collect();
+  assert_eq!(binance[0].amount, dec!(3));
+  assert_eq!(summary.bids.len(), 2);
+  assert!(summary.bids.iter().all(|l| l.price == dec!(0.07099)));
+  }
+
+#[test]
+fn consolidated_levels() {
+  let mut book = AggregatedBook::new(String::from("ethbtc"));
+  book.insert_level(OrderSide::Ask, Level { exchange: String::from("binance"), price: dec!(101), amount: dec!(1) });
+  book.insert_level(OrderSide::Ask, Level { exchange: String::from("bitstamp"), price: dec!(101), amount: dec!(2.5) });
+  book.insert_level(OrderSide::Ask, Level { exchange: String::from("bitstamp"), price: dec!(102), amount: dec!(1) });
+  book.insert_level(OrderSide::Bid, Level { exchange: String::from("binance"), price: dec!(99), amount: dec!(0) });
+  let summary = book.get_consolidated_levels(1);
+  assert_eq!(summary.consolidated_asks.len(), 1);
+  assert_eq!(summary.consolidated_asks[0].amount, dec!(3.5));
+  assert_eq!(summary.consolidated_asks[0].venues["bitstamp"], dec!(2.5));
+  assert!(summary.consolidated_bids.is_empty());
+  }
+
+#[test]
+fn venue_snapshot() {
+  let mut book = AggregatedBook::new(String::from("ethbtc"));
+  book.insert_level(OrderSide::Ask, Level { exchange: String::from("binance"), price: dec!(101), amount: dec!(1) });
+  book.insert_level(OrderSide::Ask, Level { exchange: String::from("bitstamp"), price: dec!(101), amount: dec!(2) });
+  book.insert_level(OrderSide::Ask, Level { exchange: String::from("binance"), price: dec!(103), amount: dec!(1) });
+  book.apply_snapshot(OrderBook {
+    exchange: String::from("binance"),
+    asks: vec![Level { exchange: String::from("binance"), price: dec!(102), amount: dec!(5) }],
+    bids: vec![],
+    update: VenueUpdate { update_id: 7, exchange_time: 0, receive_time: 1 },
+  });
+  assert_eq!(book.venue_levels(OrderSide::Ask, "binance"), vec![(dec!(102), dec!(5))]);
+  assert_eq!(book.venue_levels(OrderSide::Ask, "bitstamp"), vec![(dec!(101), dec!(2))]);
+  assert!(!book.asks.contains_key(&dec!(103)));
+  let summary = book.get_levels(10);
+  assert_eq!((summary.sequence, summary.venues["binance"].update.update_id), (1, 7));
+  assert!(summary.venues["binance"].included);
+
+  let status = |state| VenueStatus { state, last_update_id: 7, gaps: 0, resyncs: 0, buffered: 0 };
+  book.venues.insert(String::from("binance"), status(SyncState::Synced));
+  assert!(book.get_levels(10).venues["binance"].included);
+  book.venues.insert(String::from("binance"), status(SyncState::Resyncing));
+  let summary = book.get_levels(10);
+  assert!(!summary.venues["binance"].included);
+  assert_eq!(summary.venues["binance"].state, Some(SyncState::Resyncing));
+  }
+
+#[test]
+fn removed_diff_levels() {
+  let mut book = AggregatedBook::new(String::from("ethbtc"));
+  let diff = |id: i64, b: &str, a: &str| format!(
+    r#"{{"e":"depthUpdate","E":1,"s":"ETHBTC","U":{},"u":{},"b":{},"a":{}}}"#, id, id, b, a);
+  let (first, _) = parse_book(&Exchange::Binance(String::new()), &diff(1, r#"[["99","1"],["98","1"]]"#, r#"[["101","1"],["102","1"]]"#)).unwrap();
+  book.update(first);
+  let (removal, _) = parse_book(&Exchange::Binance(String::new()), &diff(2, r#"[["99","0"]]"#, r#"[["101","0"]]"#)).unwrap();
+  book.update(removal);
+  assert!(!book.asks.contains_key(&dec!(101)) && !book.bids.contains_key(&dec!(99)));
+  assert_eq!(book.spread, dec!(4));
+  let summary = book.get_levels(1);
+  assert_eq!((summary.asks[0].price, summary.bids[0].price), (dec!(102), dec!(98)));
+  }
+
+#[test]
+fn tick_buckets() {
+  let mut book = AggregatedBook::new(String::from("ethbtc"));
+  book.tick_size = dec!(0.00001);
+  let diff = |id: i64, b: &str, a: &str| format!(
+    r#"{{"e":"depthUpdate","E":1,"s":"ETHBTC","U":{},"u":{},"b":{},"a":{}}}"#, id, id, b, a);
+  let (first, _) = parse_book(&Exchange::Binance(String::new()),
+    &diff(1, r#"[["0.070999","1"],["0.070991","3"]]"#, r#"[["0.071001","1"],["0.071004","2"]]"#)).unwrap();
+  book.update(first);
+  let on_tick = book.clone().on_tick().get_levels(10);
+  assert_eq!((on_tick.asks.len(), on_tick.asks[0].price, on_tick.asks[0].amount), (1, dec!(0.07101), dec!(3)));
+  assert_eq!((on_tick.bids[0].price, on_tick.bids[0].amount), (dec!(0.07099), dec!(4)));
+  // a diff only replaces its own prices of a bucket
+  let (removal, _) = parse_book(&Exchange::Binance(String::new()), &diff(2, "[]", r#"[["0.071001","0"]]"#)).unwrap();
+  book.update(removal);
+  assert_eq!(book.get_levels(10).asks[0].price, dec!(0.071004));
+  let on_tick = book.on_tick();
+  assert_eq!(on_tick.get_levels(10).asks[0].amount, dec!(2));
+  assert_eq!(on_tick.spread, dec!(0.00002));
+  }
+}
@@ -0,0 +1,144 @@
+use crate::exchange_tools::{AggregatedBook, Side};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::BTreeMap;
+
+// quote left after dividing by a price that does not divide evenly, no fill of its own
+const QUOTE_DUST: Decimal = dec!(0.000000000001);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantityUnit {
+  Base,
+  Quote,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VenueFill {
+  pub exchange: String,
+  pub amount: Decimal,
+  pub notional: Decimal,
+}
+
+impl VenueFill {
+  pub fn average_price(&self) -> Decimal {
+    if self.amount.is_zero() { dec!(0) } else { self.notional / self.amount }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionEstimate {
+  pub filled: Decimal,
+  pub notional: Decimal,
+  pub average_price: Decimal,
+  pub worst_price: Decimal,
+  pub mid: Decimal,
+  // positive when the average price is worse than the mid
+  pub slippage_bps: Decimal,
+  // false when the book is too thin for the whole quantity
+  pub complete: bool,
+  pub venues: Vec<VenueFill>,
+}
+
+// walks the merged book from the best price, taking every exchange at a price level
+pub fn estimate(book: &AggregatedBook, side: Side, quantity: Decimal, unit: QuantityUnit) -> ExecutionEstimate {
+  let mut levels: Vec<_> = book.dump_levels(side.book_side()).collect();
+  if side == Side::Sell {
+    levels.reverse();
+  }
+  let mut venues: BTreeMap<String, VenueFill> = BTreeMap::new();
+  let mut left = quantity;
+  let (mut filled, mut notional, mut worst_price) = (dec!(0), dec!(0), dec!(0));
+  'levels: for mut price_level in levels {
+    price_level.sort_by(|a, b| a.exchange.cmp(&b.exchange));
+    for level in price_level {
+      if left <= dec!(0) {
+        break 'levels;
+      }
+      let amount = match unit {
+        QuantityUnit::Base => left.min(level.amount),
+        QuantityUnit::Quote => (left / level.price).min(level.amount),
+      };
+      let venue = venues.entry(level.exchange.clone()).or_insert(VenueFill { exchange: level.exchange, ..Default::default() });
+      venue.amount += amount;
+      venue.notional += amount * level.price;
+      filled += amount;
+      notional += amount * level.price;
+      worst_price = level.price;
+      left -= match unit {
+        QuantityUnit::Base => amount,
+        QuantityUnit::Quote => amount * level.price,
+      };
+      if unit == QuantityUnit::Quote && left < QUOTE_DUST {
+        left = dec!(0);
+      }
+    }
+  }
+  let mid = book.metrics.mid;
+  let average_price = if filled.is_zero() { dec!(0) } else { notional / filled };
+  let slippage_bps = if mid.is_zero() || filled.is_zero() {
+    dec!(0)
+  } else {
+    match side {
+      Side::Buy => (average_price - mid) / mid * dec!(10000),
+      Side::Sell => (mid - average_price) / mid * dec!(10000),
+    }
+  };
+  ExecutionEstimate {
+    filled,
+    notional,
+    average_price,
+    worst_price,
+    mid,
+    slippage_bps,
+    complete: left <= dec!(0),
+    venues: venues.into_values().collect(),
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{AggregatedBook, Level, OrderSide, Side};
+  use crate::metrics;
+  use super::{estimate, QuantityUnit};
+  use rust_decimal_macros::dec;
+
+  #[test]
+  fn walk_merged_book() {
+    let mut book = AggregatedBook::new(String::from("ethbtc"));
+    for (side, exchange, price, amount) in [
+      (OrderSide::Bid, "binance", dec!(99), dec!(1)),
+      (OrderSide::Ask, "binance", dec!(101), dec!(1)),
+      (OrderSide::Ask, "bitstamp", dec!(101), dec!(1)),
+      (OrderSide::Ask, "bitstamp", dec!(102), dec!(2)),
+    ] {
+      book.insert_level(side, Level { exchange: exchange.to_owned(), price, amount });
+    }
+    book.metrics = metrics::compute(&book, &book.metrics_config);
+
+    let buy = estimate(&book, Side::Buy, dec!(3), QuantityUnit::Base);
+    assert!(buy.complete);
+    assert_eq!(buy.notional, dec!(304));
+    assert_eq!(buy.average_price, dec!(304) / dec!(3));
+    assert_eq!(buy.worst_price, dec!(102));
+    assert_eq!(buy.venues.len(), 2);
+    assert_eq!(buy.venues[0].amount, dec!(1));
+    assert_eq!(buy.venues[1].amount, dec!(2));
+    assert_eq!(buy.venues[1].notional, dec!(203));
+
+    let quote = estimate(&book, Side::Buy, dec!(202), QuantityUnit::Quote);
+    assert_eq!(quote.filled, dec!(2));
+    assert_eq!(quote.slippage_bps, dec!(100));
+
+    // 1 / 3 of a base unit leaves a rounding residue of quote
+    let mut thirds = AggregatedBook::new(String::from("ethbtc"));
+    thirds.insert_level(OrderSide::Ask, Level { exchange: String::from("binance"), price: dec!(3), amount: dec!(10) });
+    thirds.insert_level(OrderSide::Ask, Level { exchange: String::from("binance"), price: dec!(4), amount: dec!(10) });
+    let quote = estimate(&thirds, Side::Buy, dec!(10), QuantityUnit::Quote);
+    assert!(quote.complete);
+    assert_eq!(quote.worst_price, dec!(3));
+
+    let sell = estimate(&book, Side::Sell, dec!(2), QuantityUnit::Base);
+    assert!(!sell.complete);
+    assert_eq!(sell.filled, dec!(1));
+  }
+}
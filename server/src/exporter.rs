@@ -0,0 +1,102 @@
+use crate::error::{self, Error};
+use crate::exchange_tools::Level;
+use crate::snapshot::SharedSnapshot;
+use chrono::{DateTime, Utc};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::task;
+use tokio::time::{self, Duration};
+
+pub const CSV_HEADER: &str = "timestamp,side,price,amount,venue";
+
+#[derive(Debug, Clone)]
+pub struct ExportConfig {
+  pub dir: PathBuf,
+  pub interval: Duration,
+  pub levels: usize,
+}
+
+// files are partitioned as <dir>/symbol=<pair>/date=<yyyy-mm-dd>/snapshots.csv
+pub fn partition_path(dir: &Path, symbol: &str, timestamp: &DateTime<Utc>) -> PathBuf {
+  dir.join(format!("symbol={}", symbol))
+    .join(format!("date={}", timestamp.format("%Y-%m-%d")))
+    .join("snapshots.csv")
+}
+
+fn csv_rows(timestamp: &DateTime<Utc>, side: &str, levels: &[Level]) -> Vec<String> {
+  let timestamp = timestamp.timestamp_millis();
+  levels.iter()
+    .map(|l| format!("{},{},{},{},{}", timestamp, side, l.price, l.amount, l.exchange))
+    .collect()
+}
+
+pub fn write_snapshot(dir: &Path, symbol: &str, timestamp: &DateTime<Utc>, asks: &[Level], bids: &[Level]) -> Result<(), Error> {
+  let path = partition_path(dir, symbol, timestamp);
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  let new_file = !path.exists();
+  let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+  if new_file {
+    writeln!(file, "{}", CSV_HEADER)?;
+  }
+  for row in csv_rows(timestamp, "bid", bids).into_iter().chain(csv_rows(timestamp, "ask", asks)) {
+    writeln!(file, "{}", row)?;
+  }
+  Ok(())
+}
+
+pub async fn export_snapshots(config: ExportConfig, snapshot: SharedSnapshot) -> Result<(), Error> {
+  println!("exporting top {} levels to {:?} every {:?}", config.levels, config.dir, config.interval);
+  let mut interval = time::interval(config.interval);
+  loop {
+    interval.tick().await;
+    let (symbol, summary) = {
+      let snapshot = snapshot.load();
+      (snapshot.book.currency_pair.clone(), snapshot.book.get_levels(config.levels))
+    };
+    if summary.asks.is_empty() && summary.bids.is_empty() {
+      continue;
+    }
+    // files are written off the runtime threads
+    let dir = config.dir.clone();
+    let written = task::spawn_blocking(move || {
+      write_snapshot(&dir, &symbol, &Utc::now(), &summary.asks, &summary.bids).map_err(|e| (symbol, e))
+    }).await?;
+    // the next interval tries again, e.g. after the disk was full
+    if let Err((symbol, e)) = written {
+      error::recover(&format!("export of {} failed", symbol), &e);
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::Level;
+  use super::{partition_path, write_snapshot, CSV_HEADER};
+  use chrono::{TimeZone, Utc};
+  use rust_decimal_macros::dec;
+  use std::path::Path;
+
+  #[test]
+  fn partitioned_csv() {
+    let timestamp = Utc.with_ymd_and_hms(2023, 8, 20, 12, 0, 0).unwrap();
+    assert_eq!(partition_path(Path::new("out"), "ethbtc", &timestamp),
+      Path::new("out/symbol=ethbtc/date=2023-08-20/snapshots.csv"));
+
+    let dir = std::env::temp_dir().join("book-merger-export-test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let asks = vec![Level { exchange: String::from("binance"), price: dec!(0.0712), amount: dec!(3) }];
+    let bids = vec![Level { exchange: String::from("bitstamp"), price: dec!(0.0711), amount: dec!(1.5) }];
+    write_snapshot(&dir, "ethbtc", &timestamp, &asks, &bids).unwrap();
+    write_snapshot(&dir, "ethbtc", &timestamp, &asks, &bids).unwrap();
+    let content = std::fs::read_to_string(partition_path(&dir, "ethbtc", &timestamp)).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], CSV_HEADER);
+    assert_eq!(lines[1], "1692532800000,bid,0.0711,1.5,bitstamp");
+    assert_eq!(lines[2], "1692532800000,ask,0.0712,3,binance");
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
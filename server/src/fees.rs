@@ -0,0 +1,28 @@
+use crate::exchange_tools::OrderSide;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FeeSchedule {
+  pub maker: Decimal,
+  pub taker: Decimal,
+}
+
+pub type FeeSchedules = HashMap<String, FeeSchedule>;
+
+pub fn taker_fee(fees: &FeeSchedules, exchange: &str) -> Decimal {
+  fees.get(exchange).map(|f| f.taker).unwrap_or(dec!(0))
+}
+
+pub fn maker_fee(fees: &FeeSchedules, exchange: &str) -> Decimal {
+  fees.get(exchange).map(|f| f.maker).unwrap_or(dec!(0))
+}
+
+// price we effectively pay when lifting an ask or receive when hitting a bid
+pub fn effective_price(side: OrderSide, price: Decimal, fee: Decimal) -> Decimal {
+  match side {
+    OrderSide::Ask => price * (dec!(1) + fee),
+    OrderSide::Bid => price * (dec!(1) - fee),
+  }
+}
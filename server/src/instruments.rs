@@ -0,0 +1,147 @@
+use crate::error::{ConnectorError, Error};
+use crate::exchange_tools::rest_client;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Deserialize;
+use std::str::FromStr;
+
+pub const BINANCE_EXCHANGE_INFO: &str = "https://api.binance.com/api/v3/exchangeInfo?symbol={}";
+pub const BITSTAMP_PAIRS_INFO: &str = "https://www.bitstamp.net/api/v2/trading-pairs-info/";
+
+const QUOTE_ASSETS: [&str; 8] = ["usdt", "usdc", "busd", "btc", "eth", "usd", "eur", "gbp"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+  pub exchange: String,
+  // symbol as the exchange spells it
+  pub symbol: String,
+  pub base: String,
+  pub quote: String,
+  pub tick_size: Decimal,
+  pub lot_size: Decimal,
+}
+
+// splits a pair like ethbtc into (eth, btc) by the known quote assets
+pub fn split_pair(pair: &str) -> (String, String) {
+  let pair = pair.to_ascii_lowercase();
+  for quote in QUOTE_ASSETS {
+    if pair.len() > quote.len() && pair.ends_with(quote) {
+      return (pair[..pair.len() - quote.len()].to_owned(), quote.to_owned());
+    }
+  }
+  (pair, String::new())
+}
+
+impl Instrument {
+  // symbol mapping without exchange metadata, zero steps disable rounding
+  pub fn new(exchange: &str, pair: &str) -> Self {
+    let (base, quote) = split_pair(pair);
+    let symbol = match exchange {
+      "binance" => pair.to_ascii_uppercase(),
+      _ => pair.to_ascii_lowercase(),
+    };
+    Self { exchange: exchange.to_owned(), symbol, base, quote, tick_size: dec!(0), lot_size: dec!(0) }
+  }
+}
+
+// "exchange=symbol:tick:lot", i.e. binance=ETHBTC:0.000001:0.0001
+pub fn parse_instrument(value: &str, pair: &str) -> Option<Instrument> {
+  let (exchange, spec) = value.split_once('=')?;
+  let mut parts = spec.split(':');
+  let mut instrument = Instrument::new(&exchange.to_ascii_lowercase(), pair);
+  instrument.symbol = parts.next().filter(|s| !s.is_empty())?.to_owned();
+  instrument.tick_size = parts.next().map(Decimal::from_str).transpose().ok()?.unwrap_or(dec!(0));
+  instrument.lot_size = parts.next().map(Decimal::from_str).transpose().ok()?.unwrap_or(dec!(0));
+  Some(instrument)
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+struct BinanceFilter {
+  filterType: String,
+  tickSize: Option<Decimal>,
+  stepSize: Option<Decimal>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+struct BinanceSymbol {
+  symbol: String,
+  baseAsset: String,
+  quoteAsset: String,
+  filters: Vec<BinanceFilter>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BinanceExchangeInfo {
+  symbols: Vec<BinanceSymbol>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BitstampPairInfo {
+  name: String,
+  url_symbol: String,
+  base_decimals: u32,
+  counter_decimals: u32,
+}
+
+pub async fn fetch_binance(pair: &str) -> Result<Instrument, Error> {
+  let body = rest_client().get(BINANCE_EXCHANGE_INFO.replace("{}", &pair.to_ascii_uppercase()))
+    .send().await?.text().await?;
+  let info: BinanceExchangeInfo = serde_json::from_str(&body)?;
+  let symbol = info.symbols.into_iter().next().ok_or_else(|| ConnectorError::UnknownSymbol(pair.to_owned()))?;
+  let filter = |name: &str| symbol.filters.iter().find(|f| f.filterType == name);
+  Ok(Instrument {
+    exchange: String::from("binance"),
+    tick_size: filter("PRICE_FILTER").and_then(|f| f.tickSize).unwrap_or(dec!(0)),
+    lot_size: filter("LOT_SIZE").and_then(|f| f.stepSize).unwrap_or(dec!(0)),
+    base: symbol.baseAsset.to_ascii_lowercase(),
+    quote: symbol.quoteAsset.to_ascii_lowercase(),
+    symbol: symbol.symbol,
+  })
+}
+
+pub async fn fetch_bitstamp(pair: &str) -> Result<Instrument, Error> {
+  let body = rest_client().get(BITSTAMP_PAIRS_INFO).send().await?.text().await?;
+  let pairs: Vec<BitstampPairInfo> = serde_json::from_str(&body)?;
+  let info = pairs.into_iter()
+    .find(|p| p.url_symbol == pair.to_ascii_lowercase())
+    .ok_or_else(|| ConnectorError::UnknownSymbol(pair.to_owned()))?;
+  let (base, quote) = info.name.split_once('/').unwrap_or((info.name.as_str(), ""));
+  Ok(Instrument {
+    exchange: String::from("bitstamp"),
+    base: base.to_ascii_lowercase(),
+    quote: quote.to_ascii_lowercase(),
+    tick_size: Decimal::new(1, info.counter_decimals),
+    lot_size: Decimal::new(1, info.base_decimals),
+    symbol: info.url_symbol,
+  })
+}
+
+pub async fn fetch_instrument(exchange: &str, pair: &str) -> Result<Instrument, Error> {
+  match exchange {
+    "binance" => fetch_binance(pair).await,
+    "bitstamp" => fetch_bitstamp(pair).await,
+    _ => Ok(Instrument::new(exchange, pair)),
+  }
+}
+
+// the coarsest tick of all venues, so every venue price maps onto it
+pub fn common_tick(instruments: &[Instrument]) -> Decimal {
+  instruments.iter().map(|i| i.tick_size).max().unwrap_or(dec!(0))
+}
+
+#[cfg(test)]
+pub mod test {
+  use super::{parse_instrument, split_pair};
+  use rust_decimal_macros::dec;
+
+  #[test]
+  fn symbols_and_ticks() {
+    assert_eq!(split_pair("ETHBTC"), (String::from("eth"), String::from("btc")));
+    assert_eq!(split_pair("btcusdt"), (String::from("btc"), String::from("usdt")));
+    let instrument = parse_instrument("Binance=ETHBTC:0.00001:0.0001", "ethbtc").unwrap();
+    assert_eq!(instrument.exchange, "binance");
+    assert_eq!(instrument.tick_size, dec!(0.00001));
+  }
+}
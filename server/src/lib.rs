@@ -1,7 +1,25 @@
+pub mod aggregator;
+pub mod alerts;
+pub mod arbitrage;
 pub mod book_streamer;
+pub mod candles;
 pub mod connector;
 pub mod client;
+pub mod error;
 pub mod exchange_tools;
+pub mod execution;
+pub mod exporter;
+pub mod fees;
+pub mod instruments;
+pub mod metrics;
+pub mod paper;
+pub mod router;
+pub mod rules;
+pub mod snapshot;
+pub mod sync;
+pub mod synthetic;
+pub mod tls;
+pub mod trades;
 pub mod test;
 mod bitstamp;
 mod binance;
@@ -1,11 +1,98 @@
-use book_merger::book_streamer::BookStreamer;
-use book_merger::client::error::Error;
+use book_merger::book_streamer::{BookStreamer, Market};
+use book_merger::error::{Error, ServerError};
 use book_merger::exchange_tools::{BINANCE_WSS, BITSTAMP_WSS, Exchange};
-use clap::{Arg, App};
+use book_merger::exporter::ExportConfig;
+use book_merger::fees::FeeSchedules;
+use book_merger::instruments::{fetch_instrument, parse_instrument, Instrument};
+use book_merger::metrics::MetricsConfig;
+use book_merger::rules::parse_rule;
+use book_merger::synthetic::parse_synthetic;
+use book_merger::tls::TlsConfig;
+use book_merger::trades::BINANCE_TRADES_WSS;
+use clap::{Arg, App, ArgMatches};
+use rust_decimal::Decimal;
 use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::time::Duration;
 
-async fn grpc_server(exchanges: Vec<(Exchange, Option<String>)>, currency_pair: String) -> Result<(), Error> {
-  let mut worker = BookStreamer::new(exchanges, currency_pair);
+// parses "exchange=fee" pairs, i.e. binance=0.001
+fn parse_fees(values: Option<clap::Values>) -> Result<HashMap<String, Decimal>, Error> {
+  values.into_iter().flatten()
+    .map(|value| value.split_once('=')
+      .and_then(|(exchange, fee)| Decimal::from_str(fee).ok().map(|fee| (exchange.to_ascii_lowercase(), fee)))
+      .ok_or_else(|| ServerError::BadConfig(format!("bad fee {}, expected exchange=fee", value)).into()))
+    .collect()
+}
+
+// value of the option, `default` when it is not given, an error unless it is positive
+fn positive<T: FromStr + PartialOrd + Default>(matches: &ArgMatches, name: &str, default: T) -> Result<T, Error> {
+  match matches.value_of(name) {
+    None => Ok(default),
+    Some(value) => value.parse::<T>().ok()
+      .filter(|parsed| *parsed > T::default())
+      .ok_or_else(|| ServerError::BadConfig(format!("--{} must be positive, got {}", name, value)).into()),
+  }
+}
+
+// configured instruments of the pair, fetched from the exchanges otherwise
+async fn load_instruments(pair: &str, configured: &[&str]) -> Result<Vec<Instrument>, Error> {
+  let configured = configured.iter()
+    .map(|value| parse_instrument(value, pair)
+      .ok_or_else(|| ServerError::BadConfig(format!("bad instrument {}, expected exchange=symbol:tick:lot", value))))
+    .collect::<Result<Vec<Instrument>, ServerError>>()?;
+  let mut instruments = Vec::new();
+  for exchange in ["binance", "bitstamp"] {
+    let configured = configured.iter()
+      .find(|instrument| instrument.exchange == exchange)
+      .cloned();
+    let instrument = match configured {
+      Some(instrument) => instrument,
+      None => match fetch_instrument(exchange, pair).await {
+        Ok(instrument) => instrument,
+        Err(e) => {
+          println!("no instrument metadata for {}: {:?}", exchange, e);
+          Instrument::new(exchange, pair)
+        }
+      }
+    };
+    println!("{:?}", instrument);
+    instruments.push(instrument);
+  }
+  Ok(instruments)
+}
+
+// binance and bitstamp streams with their subscriptions
+fn exchange_streams(instruments: &[Instrument]) -> Vec<(Exchange, Option<String>)> {
+  let frmt = json!({
+    "event": "bts:subscribe",
+    "data": {
+      "channel": "order_book_{}"
+    }
+  }).to_string();
+  let subscribe_bitstamp: String = frmt.replace("{}", &instruments[1].symbol.to_ascii_lowercase());
+  let binance_wss_currency = BINANCE_WSS.replace("{}", &instruments[0].symbol.to_ascii_lowercase());
+  vec![
+    (Exchange::Binance(binance_wss_currency), None),
+    (Exchange::Bitstamp(BITSTAMP_WSS.to_owned()), Some(subscribe_bitstamp))]
+}
+
+// binance and bitstamp trade streams with their subscriptions
+fn trade_streams(instruments: &[Instrument]) -> Vec<(Exchange, Option<String>)> {
+  let subscribe_bitstamp = json!({
+    "event": "bts:subscribe",
+    "data": {
+      "channel": format!("live_trades_{}", instruments[1].symbol.to_ascii_lowercase())
+    }
+  }).to_string();
+  let binance_wss_currency = BINANCE_TRADES_WSS.replace("{}", &instruments[0].symbol.to_ascii_lowercase());
+  vec![
+    (Exchange::Binance(binance_wss_currency), None),
+    (Exchange::Bitstamp(BITSTAMP_WSS.to_owned()), Some(subscribe_bitstamp))]
+}
+
+async fn grpc_server(mut worker: BookStreamer) -> Result<(), Error> {
   worker.run().await
 }
 
@@ -20,15 +107,118 @@ async fn main() -> Result<(), Error> {
     .takes_value(true)
     .help("provides pair of currencies for orderbook data")
   )
+  .arg(Arg::new("export-dir")
+    .long("export-dir")
+    .required(false)
+    .takes_value(true)
+    .help("directory for periodic csv snapshots of the merged book")
+  )
+  .arg(Arg::new("export-interval")
+    .long("export-interval")
+    .required(false)
+    .takes_value(true)
+    .default_value("60")
+    .help("snapshot export interval in seconds")
+  )
+  .arg(Arg::new("export-levels")
+    .long("export-levels")
+    .required(false)
+    .takes_value(true)
+    .default_value("10")
+    .help("number of price levels per side in exported snapshots")
+  )
+  .arg(Arg::new("maker-fee")
+    .long("maker-fee")
+    .required(false)
+    .takes_value(true)
+    .multiple_occurrences(true)
+    .help("maker fee rate of an exchange as exchange=fee, i.e. binance=0.001")
+  )
+  .arg(Arg::new("taker-fee")
+    .long("taker-fee")
+    .required(false)
+    .takes_value(true)
+    .multiple_occurrences(true)
+    .help("taker fee rate of an exchange as exchange=fee, i.e. binance=0.001")
+  )
+  .arg(Arg::new("imbalance-levels")
+    .long("imbalance-levels")
+    .required(false)
+    .takes_value(true)
+    .default_value("5")
+    .help("number of price levels per side for the volume imbalance")
+  )
+  .arg(Arg::new("depth-bps")
+    .long("depth-bps")
+    .required(false)
+    .takes_value(true)
+    .default_value("10")
+    .help("distance from the mid price in basis points for cumulative depth")
+  )
+  .arg(Arg::new("vwap-quantity")
+    .long("vwap-quantity")
+    .required(false)
+    .takes_value(true)
+    .default_value("1")
+    .help("base quantity to fill for the bid and ask vwap")
+  )
+  .arg(Arg::new("instrument")
+    .long("instrument")
+    .required(false)
+    .takes_value(true)
+    .multiple_occurrences(true)
+    .help("instrument of an exchange as exchange=symbol:tick:lot, i.e. binance=ETHBTC:0.000001:0.0001, fetched from the exchange otherwise")
+  )
+  .arg(Arg::new("market")
+    .long("market")
+    .required(false)
+    .takes_value(true)
+    .multiple_occurrences(true)
+    .help("further pair of currencies to merge and serve, i.e. btcusdt")
+  )
+  .arg(Arg::new("synthetic")
+    .long("synthetic")
+    .required(false)
+    .takes_value(true)
+    .multiple_occurrences(true)
+    .help("synthetic pair from served legs as symbol=leg,leg[@max_quantity], i.e. ethusdt=ethbtc,btcusdt@10")
+  )
+  .arg(Arg::new("alert-rule")
+    .long("alert-rule")
+    .required(false)
+    .takes_value(true)
+    .multiple_occurrences(true)
+    .help("alert rule on the merged book, i.e. \"spread_bps > 20 for 5s\", \"top_depth < 1\" or \"stale binance > 3s\"")
+  )
+  .arg(Arg::new("alert-webhook")
+    .long("alert-webhook")
+    .required(false)
+    .takes_value(true)
+    .help("url fired and resolved rule alerts are posted to as json")
+  )
+  .arg(Arg::new("tls-cert")
+    .long("tls-cert")
+    .required(false)
+    .takes_value(true)
+    .requires("tls-key")
+    .help("pem of the server certificate, grpc is served over tls with it")
+  )
+  .arg(Arg::new("tls-key")
+    .long("tls-key")
+    .required(false)
+    .takes_value(true)
+    .requires("tls-cert")
+    .help("pem of the server private key")
+  )
+  .arg(Arg::new("tls-client-ca")
+    .long("tls-client-ca")
+    .required(false)
+    .takes_value(true)
+    .requires("tls-cert")
+    .help("pem of the CA client certificates have to be signed by, clients without one are refused")
+  )
   .get_matches();
   let mut currencies = "ethbtc";
-   
-  let frmt = json!({
-    "event": "bts:subscribe",
-    "data": {
-      "channel": "order_book_{}"
-    }
-  }).to_string();
   
   if matches.is_present("currencies") {
     if let Some(val) = matches.value_of("currencies") {
@@ -36,20 +226,85 @@ async fn main() -> Result<(), Error> {
    }
   }
 
-  let subscribe_ethbtc: String = frmt.replace("{}", &currencies.to_ascii_lowercase());
-  let binance_wss_currency = BINANCE_WSS.replace("{}", currencies);
-  let exchanges = vec![
-    (Exchange::Binance(binance_wss_currency), None),
-    (Exchange::Bitstamp(BITSTAMP_WSS.to_owned()), Some(subscribe_ethbtc))];
-  grpc_server(exchanges, String::from(currencies)).await
+  let configured: Vec<&str> = matches.values_of("instrument").into_iter().flatten().collect();
+  let instruments = load_instruments(currencies, &configured).await?;
+  let mut worker = BookStreamer::new(exchange_streams(&instruments), String::from(currencies));
+  worker.trade_exchanges = trade_streams(&instruments);
+  worker.instruments = instruments;
+  for pair in matches.values_of("market").into_iter().flatten() {
+    let instruments = load_instruments(pair, &[]).await?;
+    worker.markets.push(Market {
+      currency_pair: pair.to_ascii_lowercase(),
+      exchanges: exchange_streams(&instruments),
+      instruments,
+    });
+  }
+  for value in matches.values_of("synthetic").into_iter().flatten() {
+    let definition = parse_synthetic(value)
+      .ok_or_else(|| ServerError::BadConfig(format!("bad synthetic {}, expected symbol=leg,leg[@max_quantity]", value)))?;
+    worker.synthetics.push(definition);
+  }
+  for value in matches.values_of("alert-rule").into_iter().flatten() {
+    let rule = parse_rule(value)
+      .ok_or_else(|| ServerError::BadConfig(format!("bad alert rule {}, expected metric > threshold, i.e. spread_bps > 20 for 5s", value)))?;
+    worker.rules.push(rule);
+  }
+  worker.webhook = matches.value_of("alert-webhook").map(String::from);
+  worker.tls = matches.value_of("tls-cert").zip(matches.value_of("tls-key"))
+    .map(|(cert, key)| TlsConfig {
+      cert: PathBuf::from(cert),
+      key: PathBuf::from(key),
+      client_ca: matches.value_of("tls-client-ca").map(PathBuf::from),
+    });
+  let mut fees = FeeSchedules::new();
+  for (exchange, maker) in parse_fees(matches.values_of("maker-fee"))? {
+    fees.entry(exchange).or_default().maker = maker;
+  }
+  for (exchange, taker) in parse_fees(matches.values_of("taker-fee"))? {
+    fees.entry(exchange).or_default().taker = taker;
+  }
+  worker.fees = fees;
+  let default_metrics = MetricsConfig::default();
+  worker.metrics = MetricsConfig {
+    imbalance_levels: positive(&matches, "imbalance-levels", default_metrics.imbalance_levels)?,
+    depth_bps: positive(&matches, "depth-bps", default_metrics.depth_bps)?,
+    vwap_quantity: positive(&matches, "vwap-quantity", default_metrics.vwap_quantity)?,
+  };
+  if let Some(dir) = matches.value_of("export-dir") {
+    worker.export = Some(ExportConfig {
+      dir: PathBuf::from(dir),
+      interval: Duration::from_secs(positive(&matches, "export-interval", 60)?),
+      levels: positive(&matches, "export-levels", 10)?,
+    });
+  }
+  grpc_server(worker).await
 }
 
 #[cfg(test)]
 pub mod test {
-  use book_merger::{test::server, exchange_tools::Exchange, client::{grpc_client, error::Error}};
+  use book_merger::{test::{diff_server, server}, book_streamer::BookStreamer, exchange_tools::Exchange, tls::TlsConfig};
+  use book_merger::client::{grpc_client, ClientConfig};
+  use book_merger::error::{Error, ServerError};
   use crate::grpc_server;
+  use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
   use serde_json::json;
+  use std::path::Path;
   use tokio::{select, time, time::Duration, task::JoinError};
+  // a CA and the localhost server and client certificates it signed, as
+  // ca.pem, server.pem, server.key, client.pem and client.key in `dir`
+  fn write_certs(dir: &Path) {
+    std::fs::create_dir_all(dir).unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new());
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+    for name in ["server", "client"] {
+      let cert = Certificate::from_params(CertificateParams::new(vec![String::from("localhost")])).unwrap();
+      std::fs::write(dir.join(format!("{}.pem", name)), cert.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+      std::fs::write(dir.join(format!("{}.key", name)), cert.serialize_private_key_pem()).unwrap();
+    }
+  }
+
   #[tokio::test(flavor = "multi_thread")]
   #[serial_test::serial]
     async fn mock_servers() {
@@ -59,21 +314,21 @@ pub mod test {
       ];
       let sleep = time::sleep(Duration::from_millis(10000));
       tokio::pin!(sleep);
-      let res: Result<Result<(), Error>, JoinError>= select!{
+      let res: Result<Result<(), String>, JoinError>= select!{
         Ok(Err(e))  = tokio::spawn(async move {
-          server("127.0.0.1:8080".to_owned()).await
-         }) => { Ok(Err(e)) }
+          diff_server("127.0.0.1:8080".to_owned()).await
+         }) => { Ok(Err(e.to_string())) }
         Ok(Err(e)) = tokio::spawn(async move {
             server("127.0.0.1:3030".to_owned()).await
-        }) => { Ok(Err(e)) }
+        }) => { Ok(Err(e.to_string())) }
         Ok(Err(e)) = tokio::spawn(async move {
           time::sleep(Duration::from_millis(2000)).await;
-          grpc_server(exchanges, String::from("ethbtc")).await
-        }) => { Ok(Err(e)) }
+          grpc_server(BookStreamer::new(exchanges, String::from("ethbtc"))).await
+        }) => { Ok(Err(e.to_string())) }
         Ok(Err(e))  = tokio::spawn(async move {
           time::sleep(Duration::from_millis(3000)).await;
-          grpc_client().await
-        }) => { Ok(Err(e)) }  
+          grpc_client(ClientConfig::default()).await
+        }) => { Ok(Err(e.to_string())) }  
         () = &mut sleep => {
           println!("timer elapsed");
           Ok(Ok(()))
@@ -86,7 +341,7 @@ pub mod test {
     async fn client_server() {
       let sleep = time::sleep(Duration::from_millis(10000));
       tokio::pin!(sleep);
-      let res: Result<Result<(), Error>, JoinError> = select!{
+      let res: Result<Result<(), String>, JoinError> = select!{
         Ok(Err(e)) = tokio::spawn(async move {
           let subscribe_bitstamp: String = json!({
             "event": "bts:subscribe",
@@ -95,16 +350,16 @@ pub mod test {
             }
           }).to_string();       
           let bitstamp: String = "wss://ws.bitstamp.net".to_owned();
-          let binance: String =  "wss://stream.binance.com:9443/ws/ethbtc@depth20@100ms".to_owned();
+          let binance: String =  "wss://stream.binance.com:9443/ws/ethbtc@depth@100ms".to_owned();
           let exchanges = vec![
             (Exchange::Binance(binance), None),
             (Exchange::Bitstamp(bitstamp), Some(subscribe_bitstamp))];
-          grpc_server(exchanges, String::from("ltcbtc")).await
-        }) => { Ok(Err(e)) }
+          grpc_server(BookStreamer::new(exchanges, String::from("ltcbtc"))).await
+        }) => { Ok(Err(e.to_string())) }
         Ok(Err(e)) = tokio::spawn(async move {
           time::sleep(Duration::from_millis(1000)).await;
-          grpc_client().await
-        }) => {  Ok(Err(e)) }
+          grpc_client(ClientConfig::default()).await
+        }) => {  Ok(Err(e.to_string())) }
         () = &mut sleep => {
           println!("timer elapsed");
           Ok(Ok(()))
@@ -112,4 +367,62 @@ pub mod test {
       };
       assert!(res.unwrap().is_ok());
     }
-}
\ No newline at end of file
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial_test::serial]
+    async fn mutual_tls() {
+      let dir = std::env::temp_dir().join("book-merger-tls-test");
+      write_certs(&dir);
+      let exchanges = vec![
+        (Exchange::Other("ws://127.0.0.1:3031".to_owned()), None),
+        (Exchange::Other("ws://127.0.0.1:3031".to_owned()), None)
+      ];
+      let mut worker = BookStreamer::new(exchanges, String::from("ethbtc"));
+      worker.tls = Some(TlsConfig {
+        cert: dir.join("server.pem"),
+        key: dir.join("server.key"),
+        client_ca: Some(dir.join("ca.pem")),
+      });
+      let client = ClientConfig {
+        ca: Some(dir.join("ca.pem")),
+        identity: Some((dir.join("client.pem"), dir.join("client.key"))),
+        domain: Some(String::from("localhost")),
+        ..ClientConfig::default()
+      };
+      let anonymous = ClientConfig { identity: None, ..client.clone() };
+      let sleep = time::sleep(Duration::from_millis(6000));
+      tokio::pin!(sleep);
+      let res: Result<Result<(), String>, JoinError> = select!{
+        Ok(Err(e)) = tokio::spawn(async move {
+          server("127.0.0.1:3031".to_owned()).await
+        }) => { Ok(Err(e.to_string())) }
+        Ok(Err(e)) = tokio::spawn(async move {
+          grpc_server(worker).await
+        }) => { Ok(Err(e.to_string())) }
+        Ok(Err(e)) = tokio::spawn(async move {
+          time::sleep(Duration::from_millis(1000)).await;
+          grpc_client(client).await
+        }) => { Ok(Err(e.to_string())) }
+        () = &mut sleep => {
+          println!("timer elapsed");
+          Ok(Ok(()))
+        }
+      };
+      assert!(res.unwrap().is_ok());
+      // a client without a certificate is refused
+      let refused = time::timeout(Duration::from_millis(2000), grpc_client(anonymous)).await;
+      assert!(matches!(refused, Ok(Err(_))));
+    }
+    // a missing certificate stops the server before it connects anything
+    #[tokio::test]
+    async fn missing_certificate() {
+      let dir = std::env::temp_dir().join("book-merger-missing-cert");
+      let exchanges = vec![
+        (Exchange::Other("ws://127.0.0.1:3032".to_owned()), None),
+        (Exchange::Other("ws://127.0.0.1:3032".to_owned()), None)
+      ];
+      let mut worker = BookStreamer::new(exchanges, String::from("ethbtc"));
+      worker.tls = Some(TlsConfig { cert: dir.join("server.pem"), key: dir.join("server.key"), client_ca: None });
+      let res = time::timeout(Duration::from_millis(2000), worker.run()).await;
+      assert!(matches!(res, Ok(Err(Error::Server(ServerError::BadConfig(_))))));
+    }
+}
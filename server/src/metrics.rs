@@ -0,0 +1,133 @@
+use crate::exchange_tools::{AggregatedBook, OrderSide};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+  // number of price levels per side used for the volume imbalance
+  pub imbalance_levels: usize,
+  // distance from the mid price for cumulative depth, in basis points
+  pub depth_bps: Decimal,
+  // base quantity used for the vwap to fill
+  pub vwap_quantity: Decimal,
+}
+
+impl Default for MetricsConfig {
+  fn default() -> Self {
+    Self {
+      imbalance_levels: 5,
+      depth_bps: dec!(10),
+      vwap_quantity: dec!(1),
+    }
+  }
+}
+
+// all values are zero while a side of the book is empty,
+// vwaps are zero when the book is too thin to fill the quantity
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookMetrics {
+  pub mid: Decimal,
+  pub micro_price: Decimal,
+  pub imbalance: Decimal,
+  pub bid_depth: Decimal,
+  pub ask_depth: Decimal,
+  pub bid_vwap: Decimal,
+  pub ask_vwap: Decimal,
+}
+
+impl BookMetrics {
+  // metrics with every price multiplied by `rate`, amounts stay in the base currency
+  pub fn converted(&self, rate: Decimal) -> Self {
+    Self {
+      mid: self.mid * rate,
+      micro_price: self.micro_price * rate,
+      bid_vwap: self.bid_vwap * rate,
+      ask_vwap: self.ask_vwap * rate,
+      ..self.clone()
+    }
+  }
+}
+
+fn vwap(levels: &[(Decimal, Decimal)], quantity: Decimal) -> Decimal {
+  if quantity <= dec!(0) {
+    return dec!(0);
+  }
+  let (mut left, mut cost) = (quantity, dec!(0));
+  for (price, amount) in levels {
+    let fill = left.min(*amount);
+    cost += fill * price;
+    left -= fill;
+    if left.is_zero() {
+      return cost / quantity;
+    }
+  }
+  dec!(0)
+}
+
+pub fn compute(book: &AggregatedBook, config: &MetricsConfig) -> BookMetrics {
+  let bids = book.price_levels(OrderSide::Bid);
+  let asks = book.price_levels(OrderSide::Ask);
+  let (best_bid, best_ask) = match (bids.first(), asks.first()) {
+    (Some(bid), Some(ask)) => (*bid, *ask),
+    _ => return BookMetrics::default(),
+  };
+  let mid = (best_bid.0 + best_ask.0) / dec!(2);
+  let micro_price = (best_bid.0 * best_ask.1 + best_ask.0 * best_bid.1) / (best_bid.1 + best_ask.1);
+
+  let bid_volume: Decimal = bids.iter().take(config.imbalance_levels).map(|l| l.1).sum();
+  let ask_volume: Decimal = asks.iter().take(config.imbalance_levels).map(|l| l.1).sum();
+  let imbalance = if (bid_volume + ask_volume).is_zero() {
+    dec!(0)
+  } else {
+    (bid_volume - ask_volume) / (bid_volume + ask_volume)
+  };
+
+  let band = mid * config.depth_bps / dec!(10000);
+  let bid_depth = bids.iter().take_while(|l| l.0 >= mid - band).map(|l| l.1).sum();
+  let ask_depth = asks.iter().take_while(|l| l.0 <= mid + band).map(|l| l.1).sum();
+
+  BookMetrics {
+    mid,
+    micro_price,
+    imbalance,
+    bid_depth,
+    ask_depth,
+    bid_vwap: vwap(&bids, config.vwap_quantity),
+    ask_vwap: vwap(&asks, config.vwap_quantity),
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{AggregatedBook, Level, OrderSide};
+  use super::{compute, MetricsConfig};
+  use rust_decimal_macros::dec;
+
+  #[test]
+  fn book_metrics() {
+    let mut book = AggregatedBook::new(String::from("ethbtc"));
+    for (side, exchange, price, amount) in [
+      (OrderSide::Bid, "binance", dec!(99.95), dec!(1)),
+      (OrderSide::Bid, "bitstamp", dec!(99.95), dec!(2)),
+      (OrderSide::Bid, "binance", dec!(99), dec!(5)),
+      (OrderSide::Ask, "binance", dec!(100.05), dec!(1)),
+      (OrderSide::Ask, "bitstamp", dec!(100.1), dec!(3)),
+    ] {
+      book.insert_level(side, Level { exchange: exchange.to_owned(), price, amount });
+    }
+    let config = MetricsConfig { imbalance_levels: 1, depth_bps: dec!(10), vwap_quantity: dec!(2) };
+    let metrics = compute(&book, &config);
+    assert_eq!(metrics.mid, dec!(100));
+    assert_eq!(metrics.micro_price, dec!(100.025));
+    assert_eq!(metrics.imbalance, dec!(0.5));
+    assert_eq!(metrics.bid_depth, dec!(3));
+    assert_eq!(metrics.ask_depth, dec!(4));
+    assert_eq!(metrics.bid_vwap, dec!(99.95));
+    assert_eq!(metrics.ask_vwap, dec!(100.075));
+    let zero = MetricsConfig { vwap_quantity: dec!(0), ..config.clone() };
+    assert_eq!(compute(&book, &zero).bid_vwap, dec!(0));
+
+    let empty = AggregatedBook::new(String::from("ethbtc"));
+    assert_eq!(compute(&empty, &config).mid, dec!(0));
+  }
+}
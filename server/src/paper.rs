@@ -0,0 +1,368 @@
+use crate::exchange_tools::{AggregatedBook, OrderSide, Side};
+use crate::fees::{self, FeeSchedules};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+// filled and cancelled orders kept to answer cancels, older ones are dropped
+pub const CLOSED_ORDERS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+  Open,
+  PartiallyFilled,
+  Filled,
+  Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+  pub exchange: String,
+  pub price: Decimal,
+  pub quantity: Decimal,
+  pub fee: Decimal,
+  pub maker: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaperOrder {
+  pub id: u64,
+  pub account: String,
+  pub side: Side,
+  pub quantity: Decimal,
+  // market orders have no limit and never rest
+  pub limit_price: Option<Decimal>,
+  pub filled: Decimal,
+  pub status: OrderStatus,
+  // displayed quantity at the limit price that was there before the order
+  pub queue_ahead: Decimal,
+  pub fills: Vec<Fill>,
+  // exchange displaying the most at the limit price, once the level has been seen
+  queue_exchange: Option<String>,
+}
+
+impl PaperOrder {
+  pub fn remaining(&self) -> Decimal {
+    self.quantity - self.filled
+  }
+
+  fn is_open(&self) -> bool {
+    matches!(self.status, OrderStatus::Open | OrderStatus::PartiallyFilled)
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Position {
+  // signed base currency position, negative when short
+  pub base: Decimal,
+  pub average_price: Decimal,
+  pub realized_pnl: Decimal,
+  pub fees: Decimal,
+}
+
+impl Position {
+  pub fn unrealized_pnl(&self, mid: Decimal) -> Decimal {
+    if mid.is_zero() { dec!(0) } else { self.base * (mid - self.average_price) }
+  }
+
+  fn apply(&mut self, side: Side, fill: &Fill) {
+    let quantity = match side {
+      Side::Buy => fill.quantity,
+      Side::Sell => -fill.quantity,
+    };
+    self.fees += fill.fee;
+    if self.base.is_zero() || self.base.is_sign_positive() == quantity.is_sign_positive() {
+      let size = self.base.abs() + quantity.abs();
+      self.average_price = (self.average_price * self.base.abs() + fill.price * quantity.abs()) / size;
+      self.base += quantity;
+      return;
+    }
+    let closed = quantity.abs().min(self.base.abs());
+    let direction = if self.base.is_sign_positive() { dec!(1) } else { dec!(-1) };
+    self.realized_pnl += closed * (fill.price - self.average_price) * direction;
+    self.base += quantity;
+    if self.base.is_zero() {
+      self.average_price = dec!(0);
+    } else if self.base.is_sign_positive() != direction.is_sign_positive() {
+      self.average_price = fill.price;
+    }
+  }
+}
+
+// simulated execution against the live merged book. Taken liquidity stays
+// consumed until the exchange changes the displayed amount of that level.
+// A resting order fills when the opposite side trades through its price, or
+// once the queue ahead of it has gone and its price level has been cleared.
+#[derive(Default)]
+pub struct PaperEngine {
+  pub fees: FeeSchedules,
+  orders: BTreeMap<u64, PaperOrder>,
+  // ids of the closed orders in `orders`, oldest first
+  closed: VecDeque<u64>,
+  positions: HashMap<String, Position>,
+  // (side, price, exchange) -> (displayed amount, consumed amount)
+  consumed: HashMap<(OrderSide, Decimal, String), (Decimal, Decimal)>,
+  next_id: u64,
+}
+
+impl PaperEngine {
+  pub fn new(fees: FeeSchedules) -> Self {
+    Self { fees, ..Default::default() }
+  }
+
+  fn available(&self, side: OrderSide, price: Decimal, exchange: &str, displayed: Decimal) -> Decimal {
+    match self.consumed.get(&(side, price, exchange.to_owned())) {
+      Some((seen, consumed)) if *seen == displayed => displayed - consumed,
+      _ => displayed,
+    }
+  }
+
+  fn consume(&mut self, side: OrderSide, price: Decimal, exchange: &str, displayed: Decimal, quantity: Decimal) {
+    let entry = self.consumed.entry((side, price, exchange.to_owned())).or_insert((displayed, dec!(0)));
+    if entry.0 != displayed {
+      *entry = (displayed, dec!(0));
+    }
+    entry.1 += quantity;
+  }
+
+  // takes displayed liquidity up to the limit price from the best level outwards
+  fn take(&mut self, book: &AggregatedBook, side: Side, quantity: Decimal, limit_price: Option<Decimal>) -> Vec<(String, Decimal, Decimal)> {
+    let book_side = side.book_side();
+    let mut levels: Vec<_> = book.dump_levels(book_side).collect();
+    if side == Side::Sell {
+      levels.reverse();
+    }
+    let mut left = quantity;
+    let mut taken = Vec::new();
+    'levels: for mut price_level in levels {
+      price_level.sort_by(|a, b| a.exchange.cmp(&b.exchange));
+      for level in price_level {
+        let crosses = match (side, limit_price) {
+          (Side::Buy, Some(limit)) => level.price <= limit,
+          (Side::Sell, Some(limit)) => level.price >= limit,
+          (_, None) => true,
+        };
+        if left <= dec!(0) || !crosses {
+          break 'levels;
+        }
+        let available = self.available(book_side, level.price, &level.exchange, level.amount);
+        let quantity = left.min(available);
+        if quantity <= dec!(0) {
+          continue;
+        }
+        self.consume(book_side, level.price, &level.exchange, level.amount, quantity);
+        left -= quantity;
+        taken.push((level.exchange, level.price, quantity));
+      }
+    }
+    taken
+  }
+
+  fn retire(&mut self, id: u64) {
+    self.closed.push_back(id);
+    while self.closed.len() > CLOSED_ORDERS {
+      if let Some(oldest) = self.closed.pop_front() {
+        self.orders.remove(&oldest);
+      }
+    }
+  }
+
+  fn fill(&mut self, id: u64, exchange: String, price: Decimal, quantity: Decimal, maker: bool) {
+    let fee_rate = if maker { fees::maker_fee(&self.fees, &exchange) } else { fees::taker_fee(&self.fees, &exchange) };
+    let fill = Fill { exchange, price, quantity, fee: price * quantity * fee_rate, maker };
+    let order = self.orders.get_mut(&id).unwrap();
+    order.filled += quantity;
+    order.status = if order.remaining() <= dec!(0) { OrderStatus::Filled } else { OrderStatus::PartiallyFilled };
+    self.positions.entry(order.account.clone()).or_default().apply(order.side, &fill);
+    order.fills.push(fill);
+    if !order.is_open() {
+      self.retire(id);
+    }
+  }
+
+  // total displayed amount at a price and the exchange displaying the most of it
+  fn displayed_at(book: &AggregatedBook, side: OrderSide, price: Decimal) -> (Decimal, Option<String>) {
+    let levels = match side {
+      OrderSide::Ask => &book.asks,
+      OrderSide::Bid => &book.bids,
+    };
+    match levels.get(&price) {
+      Some(amounts) => (
+        amounts.values().sum(),
+        amounts.iter().filter(|a| *a.1 > dec!(0)).max_by_key(|a| *a.1).map(|a| a.0.clone()),
+      ),
+      None => (dec!(0), None),
+    }
+  }
+
+  pub fn submit(&mut self, book: &AggregatedBook, account: String, side: Side, quantity: Decimal, limit_price: Option<Decimal>) -> PaperOrder {
+    self.next_id += 1;
+    let id = self.next_id;
+    let own_side = match side {
+      Side::Buy => OrderSide::Bid,
+      Side::Sell => OrderSide::Ask,
+    };
+    let (queue_ahead, queue_exchange) = limit_price.map(|p| Self::displayed_at(book, own_side, p)).unwrap_or((dec!(0), None));
+    self.orders.insert(id, PaperOrder {
+      id, account, side, quantity, limit_price,
+      filled: dec!(0),
+      status: OrderStatus::Open,
+      queue_ahead,
+      fills: Vec::new(),
+      queue_exchange,
+    });
+    for (exchange, price, taken) in self.take(book, side, quantity, limit_price) {
+      self.fill(id, exchange, price, taken, false);
+    }
+    let order = self.orders.get_mut(&id).unwrap();
+    if limit_price.is_none() && order.is_open() {
+      order.status = OrderStatus::Cancelled;
+      let order = order.clone();
+      self.retire(id);
+      return order;
+    }
+    order.clone()
+  }
+
+  pub fn cancel(&mut self, account: &str, id: u64) -> Option<PaperOrder> {
+    let order = self.orders.get_mut(&id).filter(|o| o.account == account)?;
+    if !order.is_open() {
+      return Some(order.clone());
+    }
+    order.status = OrderStatus::Cancelled;
+    let order = order.clone();
+    self.retire(id);
+    Some(order)
+  }
+
+  pub fn on_book_update(&mut self, book: &AggregatedBook) {
+    let open: Vec<u64> = self.orders.values().filter(|o| o.is_open()).map(|o| o.id).collect();
+    for id in open {
+      let order = self.orders[&id].clone();
+      let limit = match order.limit_price {
+        Some(limit) => limit,
+        None => continue,
+      };
+      // the opposite side moved through the resting price
+      for (exchange, _, taken) in self.take(book, order.side, order.remaining(), Some(limit)) {
+        self.fill(id, exchange, limit, taken, true);
+      }
+      let own_side = match order.side {
+        Side::Buy => OrderSide::Bid,
+        Side::Sell => OrderSide::Ask,
+      };
+      let (displayed, exchange) = Self::displayed_at(book, own_side, limit);
+      let order = self.orders.get_mut(&id).unwrap();
+      if !order.is_open() {
+        continue;
+      }
+      order.queue_ahead = order.queue_ahead.min(displayed);
+      if displayed > dec!(0) {
+        order.queue_exchange = exchange;
+        continue;
+      }
+      let best = book.price_levels(own_side).first().map(|l| l.0);
+      let cleared = match (order.side, best) {
+        (Side::Buy, Some(best)) => best < limit,
+        (Side::Sell, Some(best)) => best > limit,
+        (_, None) => true,
+      };
+      if let (Some(exchange), true) = (order.queue_exchange.clone(), cleared) {
+        let remaining = order.remaining();
+        self.fill(id, exchange, limit, remaining, true);
+      }
+    }
+  }
+
+  pub fn position(&self, account: &str) -> Position {
+    self.positions.get(account).cloned().unwrap_or_default()
+  }
+
+  pub fn open_orders(&self, account: &str) -> Vec<PaperOrder> {
+    self.orders.values().filter(|o| o.account == account && o.is_open()).cloned().collect()
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{AggregatedBook, Level, OrderSide, Side};
+  use super::{OrderStatus, PaperEngine, CLOSED_ORDERS};
+  use rust_decimal_macros::dec;
+
+  fn book(levels: &[(OrderSide, &str, rust_decimal::Decimal, rust_decimal::Decimal)]) -> AggregatedBook {
+    let mut book = AggregatedBook::new(String::from("ethbtc"));
+    for (side, exchange, price, amount) in levels {
+      book.insert_level(*side, Level { exchange: exchange.to_string(), price: *price, amount: *amount });
+    }
+    book
+  }
+
+  #[test]
+  fn market_and_resting_orders() {
+    let mut engine = PaperEngine::default();
+    let live = book(&[
+      (OrderSide::Ask, "binance", dec!(101), dec!(1)),
+      (OrderSide::Ask, "bitstamp", dec!(102), dec!(1)),
+      (OrderSide::Bid, "binance", dec!(99), dec!(2)),
+    ]);
+    let market = engine.submit(&live, String::from("a"), Side::Buy, dec!(1.5), None);
+    assert_eq!(market.status, OrderStatus::Filled);
+    assert_eq!(market.fills.len(), 2);
+    // displayed liquidity at 101 was consumed by the first order
+    let second = engine.submit(&live, String::from("a"), Side::Buy, dec!(1), None);
+    assert_eq!(second.filled, dec!(0.5));
+    assert_eq!(second.status, OrderStatus::Cancelled);
+    assert_eq!(engine.position("a").base, dec!(2));
+    assert_eq!(engine.position("a").average_price, dec!(101.5));
+
+    let resting = engine.submit(&live, String::from("a"), Side::Sell, dec!(2), Some(dec!(103)));
+    assert_eq!(resting.status, OrderStatus::Open);
+    let moved = book(&[
+      (OrderSide::Ask, "binance", dec!(104), dec!(1)),
+      (OrderSide::Bid, "binance", dec!(103.5), dec!(2)),
+    ]);
+    engine.on_book_update(&moved);
+    let position = engine.position("a");
+    assert_eq!(position.base, dec!(0));
+    assert_eq!(position.realized_pnl, dec!(3));
+    assert!(engine.open_orders("a").is_empty());
+  }
+
+  #[test]
+  fn queue_position() {
+    let mut engine = PaperEngine::default();
+    let live = book(&[
+      (OrderSide::Ask, "binance", dec!(101), dec!(1)),
+      (OrderSide::Bid, "binance", dec!(99), dec!(2)),
+    ]);
+    let order = engine.submit(&live, String::from("b"), Side::Buy, dec!(1), Some(dec!(99)));
+    assert_eq!(order.queue_ahead, dec!(2));
+    engine.on_book_update(&book(&[
+      (OrderSide::Ask, "binance", dec!(101), dec!(1)),
+      (OrderSide::Bid, "binance", dec!(99), dec!(0.5)),
+    ]));
+    assert_eq!(engine.open_orders("b")[0].queue_ahead, dec!(0.5));
+    engine.on_book_update(&book(&[
+      (OrderSide::Ask, "binance", dec!(101), dec!(1)),
+      (OrderSide::Bid, "binance", dec!(98), dec!(3)),
+    ]));
+    assert!(engine.open_orders("b").is_empty());
+    assert_eq!(engine.position("b").base, dec!(1));
+    assert_eq!(engine.position("b").average_price, dec!(99));
+  }
+
+  #[test]
+  fn closed_order_history() {
+    let mut engine = PaperEngine::default();
+    let empty = book(&[]);
+    let resting = engine.submit(&empty, String::from("c"), Side::Buy, dec!(1), Some(dec!(99)));
+    for _ in 0..=CLOSED_ORDERS {
+      assert_eq!(engine.submit(&empty, String::from("c"), Side::Buy, dec!(1), None).status, OrderStatus::Cancelled);
+    }
+    // the oldest market order is gone, the resting one stays
+    assert_eq!(engine.orders.len(), CLOSED_ORDERS + 1);
+    assert!(engine.cancel("c", resting.id + 1).is_none());
+    assert_eq!(engine.cancel("c", resting.id).unwrap().status, OrderStatus::Cancelled);
+    assert_eq!(engine.orders.len(), CLOSED_ORDERS);
+  }
+}
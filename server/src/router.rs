@@ -0,0 +1,227 @@
+use crate::exchange_tools::{AggregatedBook, Level, Side};
+use crate::instruments::Instrument;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// trading constraints of a venue, a zero step disables rounding
+// and a missing balance means the venue is not limited by funds.
+// None of them may be negative
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VenueConstraints {
+  pub min_size: Decimal,
+  pub tick_size: Decimal,
+  pub lot_size: Decimal,
+  // quote currency for buys, base currency for sells
+  pub balance: Option<Decimal>,
+}
+
+impl VenueConstraints {
+  // the name of the first negative constraint
+  pub fn negative(&self) -> Option<&'static str> {
+    [
+      ("min_size", self.min_size),
+      ("tick_size", self.tick_size),
+      ("lot_size", self.lot_size),
+      ("balance", self.balance.unwrap_or_default()),
+    ].into_iter().find(|(_, value)| *value < Decimal::ZERO).map(|(name, _)| name)
+  }
+}
+
+impl From<&Instrument> for VenueConstraints {
+  fn from(instrument: &Instrument) -> Self {
+    Self {
+      min_size: instrument.lot_size,
+      tick_size: instrument.tick_size,
+      lot_size: instrument.lot_size,
+      balance: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParentOrder {
+  pub side: Side,
+  pub quantity: Decimal,
+  pub limit_price: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChildOrder {
+  pub exchange: String,
+  pub side: Side,
+  pub price: Decimal,
+  pub quantity: Decimal,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoutePlan {
+  pub orders: Vec<ChildOrder>,
+  pub unallocated: Decimal,
+}
+
+pub fn round_down(value: Decimal, step: Decimal) -> Decimal {
+  if step.is_zero() { value } else { (value / step).floor() * step }
+}
+
+pub fn round_up(value: Decimal, step: Decimal) -> Decimal {
+  if step.is_zero() { value } else { (value / step).ceil() * step }
+}
+
+#[derive(Default)]
+struct Allocation {
+  quantity: Decimal,
+  worst_price: Decimal,
+  spent: Decimal,
+}
+
+// allocations of the venues along the book, kept on the lot size of the
+// venue so that what is rounded off is left to the next levels
+fn walk(
+  levels: &[Vec<Level>],
+  order: &ParentOrder,
+  constraints: &HashMap<String, VenueConstraints>,
+  excluded: &HashSet<String>) -> BTreeMap<String, Allocation> {
+  let default_constraints = VenueConstraints::default();
+  let mut allocations: BTreeMap<String, Allocation> = BTreeMap::new();
+  let mut left = order.quantity;
+  'levels: for price_level in levels {
+    for level in price_level {
+      let within_limit = match (order.side, order.limit_price) {
+        (Side::Buy, Some(limit)) => level.price <= limit,
+        (Side::Sell, Some(limit)) => level.price >= limit,
+        (_, None) => true,
+      };
+      if left <= dec!(0) || !within_limit {
+        break 'levels;
+      }
+      if excluded.contains(&level.exchange) {
+        continue;
+      }
+      let venue = constraints.get(&level.exchange).unwrap_or(&default_constraints);
+      // a level the venue tick would price past the limit can not be taken there
+      let tick_within_limit = match (order.side, order.limit_price) {
+        (Side::Buy, Some(limit)) => round_up(level.price, venue.tick_size) <= limit,
+        (Side::Sell, Some(limit)) => round_down(level.price, venue.tick_size) >= limit,
+        (_, None) => true,
+      };
+      if !tick_within_limit {
+        continue;
+      }
+      let allocation = allocations.entry(level.exchange.clone()).or_default();
+      let affordable = match (order.side, venue.balance) {
+        (Side::Buy, Some(balance)) => (balance - allocation.spent) / level.price,
+        (Side::Sell, Some(balance)) => balance - allocation.quantity,
+        (_, None) => level.amount,
+      };
+      let quantity = left.min(level.amount).min(affordable);
+      let quantity = round_down(allocation.quantity + quantity, venue.lot_size) - allocation.quantity;
+      if quantity <= dec!(0) {
+        continue;
+      }
+      allocation.quantity += quantity;
+      allocation.spent += quantity * level.price;
+      allocation.worst_price = level.price;
+      left -= quantity;
+    }
+  }
+  allocations
+}
+
+pub fn plan(book: &AggregatedBook, order: &ParentOrder, constraints: &HashMap<String, VenueConstraints>) -> RoutePlan {
+  let mut levels: Vec<_> = book.dump_levels(order.side.book_side()).collect();
+  if order.side == Side::Sell {
+    levels.reverse();
+  }
+  let default_constraints = VenueConstraints::default();
+  // venues below their min size are left out and the book walked again
+  let mut excluded: HashSet<String> = HashSet::new();
+  let allocations = loop {
+    let allocations = walk(&levels, order, constraints, &excluded);
+    let below_min: Vec<String> = allocations.iter()
+      .filter(|(exchange, allocation)| {
+        let venue = constraints.get(*exchange).unwrap_or(&default_constraints);
+        !allocation.quantity.is_zero() && allocation.quantity < venue.min_size
+      })
+      .map(|(exchange, _)| exchange.clone())
+      .collect();
+    if below_min.is_empty() {
+      break allocations;
+    }
+    excluded.extend(below_min);
+  };
+  let mut orders = Vec::new();
+  for (exchange, allocation) in allocations {
+    if allocation.quantity.is_zero() {
+      continue;
+    }
+    let venue = constraints.get(&exchange).unwrap_or(&default_constraints);
+    // the limit must not cut off the worst level taken on the venue, the walk
+    // only took levels whose rounded price is within the limit of the parent
+    let price = match order.side {
+      Side::Buy => round_up(allocation.worst_price, venue.tick_size),
+      Side::Sell => round_down(allocation.worst_price, venue.tick_size),
+    };
+    orders.push(ChildOrder { exchange, side: order.side, price, quantity: allocation.quantity });
+  }
+  let allocated: Decimal = orders.iter().map(|o| o.quantity).sum();
+  RoutePlan { orders, unallocated: order.quantity - allocated }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{AggregatedBook, Level, OrderSide, Side};
+  use super::{plan, ParentOrder, VenueConstraints};
+  use rust_decimal_macros::dec;
+  use std::collections::HashMap;
+
+  #[test]
+  fn route_with_constraints() {
+    let mut book = AggregatedBook::new(String::from("ethbtc"));
+    for (exchange, price, amount) in [
+      ("binance", dec!(0.07101), dec!(1.5)),
+      ("bitstamp", dec!(0.07102), dec!(2)),
+      ("binance", dec!(0.07103), dec!(5)),
+    ] {
+      book.insert_level(OrderSide::Ask, Level { exchange: exchange.to_owned(), price, amount });
+    }
+    let constraints = HashMap::from([
+      (String::from("binance"), VenueConstraints { min_size: dec!(0.001), tick_size: dec!(0.00001), lot_size: dec!(0.001), balance: None }),
+      (String::from("bitstamp"), VenueConstraints { min_size: dec!(0.5), tick_size: dec!(0.0001), lot_size: dec!(0.01), balance: Some(dec!(0.1)) }),
+    ]);
+    let order = ParentOrder { side: Side::Buy, quantity: dec!(4), limit_price: None };
+    let route = plan(&book, &order, &constraints);
+    assert_eq!(route.orders.len(), 2);
+    // bitstamp can afford 0.1 / 0.07102 = 1.408... which is rounded down to the lot,
+    // binance takes the rest
+    assert_eq!(route.orders[1].exchange, "bitstamp");
+    assert_eq!(route.orders[1].quantity, dec!(1.4));
+    assert_eq!(route.orders[1].price, dec!(0.0711));
+    assert_eq!(route.orders[0].exchange, "binance");
+    assert_eq!(route.orders[0].price, dec!(0.07103));
+    assert_eq!(route.orders[0].quantity + route.orders[1].quantity + route.unallocated, dec!(4));
+
+    let limited = ParentOrder { side: Side::Buy, quantity: dec!(4), limit_price: Some(dec!(0.07101)) };
+    let route = plan(&book, &limited, &constraints);
+    assert_eq!(route.orders.len(), 1);
+    assert_eq!(route.unallocated, dec!(2.5));
+
+    // the bitstamp tick would round the price past the limit, its level is skipped
+    let limited = ParentOrder { side: Side::Buy, quantity: dec!(2), limit_price: Some(dec!(0.07102)) };
+    let route = plan(&book, &limited, &constraints);
+    assert_eq!(route.orders.len(), 1);
+    assert_eq!((route.orders[0].exchange.as_str(), route.orders[0].quantity), ("binance", dec!(1.5)));
+    assert_eq!(route.unallocated, dec!(0.5));
+    assert!(route.orders.iter().all(|o| o.price <= dec!(0.07102)
+      && o.price >= book.venue_levels(OrderSide::Ask, &o.exchange)[0].0));
+
+    // what bitstamp loses to its lot and balance is routed to binance
+    let order = ParentOrder { side: Side::Buy, quantity: dec!(3.4), limit_price: None };
+    let route = plan(&book, &order, &constraints);
+    assert_eq!(route.unallocated, dec!(0));
+    assert_eq!(route.orders[0].quantity, dec!(2));
+
+    let negative = VenueConstraints { lot_size: dec!(-0.01), ..VenueConstraints::default() };
+    assert_eq!(negative.negative(), Some("lot_size"));
+  }
+}
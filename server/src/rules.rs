@@ -0,0 +1,213 @@
+use crate::alerts::{Alert, AlertKind, RuleAlert};
+use crate::error::Error;
+use crate::exchange_tools::{AggregatedBook, OrderSide};
+use num_traits::cast::ToPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Metric {
+  // spread in basis points of the mid price
+  SpreadBps,
+  // smaller of the amounts at the best bid and the best ask
+  TopDepth,
+  // seconds since the last update of a venue
+  Stale(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+  Above,
+  Below,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+  // the rule as configured, names the alerts
+  pub name: String,
+  pub metric: Metric,
+  pub comparison: Comparison,
+  pub threshold: Decimal,
+  // how long the condition has to hold before the alert fires, in milliseconds
+  pub duration: i64,
+}
+
+// seconds, i.e. 5s, or milliseconds, i.e. 500ms
+fn parse_duration(value: &str) -> Option<i64> {
+  if let Some(millis) = value.strip_suffix("ms") {
+    return millis.parse().ok();
+  }
+  let seconds = Decimal::from_str(value.strip_suffix('s')?).ok()?;
+  (seconds * dec!(1000)).to_i64()
+}
+
+// "spread_bps > 20 for 5s", "top_depth < 1", "stale binance > 3s"
+pub fn parse_rule(value: &str) -> Option<Rule> {
+  let (condition, duration) = match value.split_once(" for ") {
+    Some((condition, duration)) => (condition, parse_duration(duration.trim())?),
+    None => (value, 0),
+  };
+  let mut parts = condition.split_whitespace();
+  let metric = match parts.next()? {
+    "spread_bps" => Metric::SpreadBps,
+    "top_depth" => Metric::TopDepth,
+    "stale" => Metric::Stale(parts.next()?.to_ascii_lowercase()),
+    _ => return None,
+  };
+  let comparison = match parts.next()? {
+    ">" => Comparison::Above,
+    "<" => Comparison::Below,
+    _ => return None,
+  };
+  let threshold = parts.next()?;
+  let threshold = match metric {
+    Metric::Stale(_) => Decimal::from(parse_duration(threshold)?) / dec!(1000),
+    _ => Decimal::from_str(threshold).ok()?,
+  };
+  if parts.next().is_some() {
+    return None;
+  }
+  Some(Rule { name: value.trim().to_owned(), metric, comparison, threshold, duration })
+}
+
+#[derive(Debug, Clone, Default)]
+struct RuleState {
+  // since when the condition holds
+  since: Option<i64>,
+  firing: bool,
+}
+
+pub struct RulesEngine {
+  rules: Vec<Rule>,
+  states: Vec<RuleState>,
+  // venues without any update are stale since the engine started
+  started: i64,
+}
+
+impl RulesEngine {
+  pub fn new(rules: Vec<Rule>, started: i64) -> Self {
+    let states = vec![RuleState::default(); rules.len()];
+    Self { rules, states, started }
+  }
+
+  // None while the metric is undefined, i.e. on an empty book
+  fn value(&self, metric: &Metric, book: &AggregatedBook, now: i64) -> Option<Decimal> {
+    match metric {
+      Metric::SpreadBps => {
+        if book.metrics.mid.is_zero() {
+          return None;
+        }
+        Some(book.spread / book.metrics.mid * dec!(10000))
+      },
+      Metric::TopDepth => {
+        let bid = book.price_levels(OrderSide::Bid).first()?.1;
+        let ask = book.price_levels(OrderSide::Ask).first()?.1;
+        Some(bid.min(ask))
+      },
+      Metric::Stale(exchange) => {
+        let last_update = book.last_updates.get(exchange).copied().unwrap_or(self.started);
+        Some(Decimal::from(now - last_update) / dec!(1000))
+      },
+    }
+  }
+
+  // alerts for the rules that fired or resolved since the last evaluation
+  pub fn evaluate(&mut self, book: &AggregatedBook, now: i64) -> Vec<AlertKind> {
+    let mut events = Vec::new();
+    for (i, rule) in self.rules.iter().enumerate() {
+      let value = self.value(&rule.metric, book, now);
+      let holds = match (value, rule.comparison) {
+        (Some(value), Comparison::Above) => value > rule.threshold,
+        (Some(value), Comparison::Below) => value < rule.threshold,
+        (None, _) => false,
+      };
+      let state = &mut self.states[i];
+      let alert = RuleAlert { rule: rule.name.clone(), value: value.unwrap_or_default() };
+      if !holds {
+        state.since = None;
+        if state.firing {
+          state.firing = false;
+          events.push(AlertKind::RuleResolved(alert));
+        }
+        continue;
+      }
+      let since = *state.since.get_or_insert(now);
+      if !state.firing && now - since >= rule.duration {
+        state.firing = true;
+        events.push(AlertKind::RuleFired(alert));
+      }
+    }
+    events
+  }
+}
+
+pub async fn post_webhook(client: &reqwest::Client, url: &str, alert: &Alert) -> Result<(), Error> {
+  client.post(url)
+    .header("content-type", "application/json")
+    .body(serde_json::to_string(alert)?)
+    .send()
+    .await?
+    .error_for_status()?;
+  Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::alerts::{Alert, AlertKind};
+  use crate::exchange_tools::{AggregatedBook, Level, OrderSide};
+  use super::{parse_rule, post_webhook, Metric, RulesEngine};
+  use rust_decimal_macros::dec;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+
+  #[tokio::test]
+  async fn rules_and_webhook() {
+    assert_eq!(parse_rule("stale binance > 3s").unwrap().metric, Metric::Stale(String::from("binance")));
+    assert!(parse_rule("spread_bps >> 20").is_none());
+    let spread = parse_rule("spread_bps > 20 for 5s").unwrap();
+    assert_eq!(spread.duration, 5000);
+    let mut engine = RulesEngine::new(vec![spread, parse_rule("stale bitstamp > 3s").unwrap()], 0);
+
+    let mut book = AggregatedBook::new(String::from("ethbtc"));
+    // no top depth before the book has both sides
+    let mut depth = RulesEngine::new(vec![parse_rule("top_depth < 1").unwrap()], 0);
+    assert!(depth.evaluate(&book, 1000).is_empty());
+    book.insert_level(OrderSide::Bid, Level { exchange: String::from("binance"), price: dec!(99.5), amount: dec!(1) });
+    assert!(depth.evaluate(&book, 2000).is_empty());
+    book.insert_level(OrderSide::Ask, Level { exchange: String::from("binance"), price: dec!(100.5), amount: dec!(1) });
+    book.spread = dec!(1);
+    book.metrics.mid = dec!(100);
+    book.last_updates.insert(String::from("bitstamp"), 0);
+    // 100 bps from 1000 on, fires 5 seconds later
+    assert!(engine.evaluate(&book, 1000).is_empty());
+    let fired = engine.evaluate(&book, 6000);
+    assert_eq!(fired.len(), 2);
+    assert!(matches!(&fired[0], AlertKind::RuleFired(alert) if alert.value == dec!(100)));
+    assert!(engine.evaluate(&book, 7000).is_empty());
+    book.spread = dec!(0.1);
+    book.last_updates.insert(String::from("bitstamp"), 7000);
+    let resolved = engine.evaluate(&book, 8000);
+    assert_eq!(resolved.len(), 2);
+    assert!(matches!(&resolved[1], AlertKind::RuleResolved(alert) if alert.rule == "stale bitstamp > 3s"));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+    let stub = tokio::spawn(async move {
+      let (mut socket, _) = listener.accept().await.unwrap();
+      let mut received = Vec::new();
+      let mut buffer = [0; 1024];
+      while !String::from_utf8_lossy(&received).contains("RuleResolved") {
+        let read = socket.read(&mut buffer).await.unwrap();
+        received.extend_from_slice(&buffer[..read]);
+      }
+      socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+      String::from_utf8_lossy(&received).into_owned()
+    });
+    let alert = Alert { symbol: String::from("ethbtc"), time: 8000, kind: resolved[0].clone() };
+    post_webhook(&reqwest::Client::new(), &url, &alert).await.unwrap();
+    let request = stub.await.unwrap();
+    assert!(request.starts_with("POST /alerts"));
+    assert!(request.contains("spread_bps > 20 for 5s"));
+  }
+}
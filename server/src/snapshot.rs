@@ -0,0 +1,66 @@
+use crate::exchange_tools::{AggregatedBook, Summary};
+use arc_swap::ArcSwap;
+use chrono::Utc;
+use std::sync::Arc;
+
+// levels per side of the summary built with every snapshot
+pub const SUMMARY_LEVELS: usize = 10;
+// price levels per side a snapshot keeps, the deepest a subscriber can request.
+// Deeper levels stay in the aggregator so publishing does not copy them
+pub const SNAPSHOT_LEVELS: usize = 500;
+
+// immutable state of a book after one update, shared by all readers
+pub struct BookSnapshot {
+  pub book: AggregatedBook,
+  // summary of a request without options, built once instead of per subscriber
+  pub summary: Summary,
+}
+
+impl BookSnapshot {
+  pub fn new(book: AggregatedBook) -> Self {
+    let mut summary = book.get_levels(SUMMARY_LEVELS);
+    summary.publish_time = Utc::now().timestamp_millis();
+    Self { book, summary }
+  }
+}
+
+// latest snapshot of a book, loading it never waits for the writer and
+// a loaded snapshot stays valid while newer ones are published
+pub type SharedSnapshot = Arc<ArcSwap<BookSnapshot>>;
+
+pub fn shared(book: AggregatedBook) -> SharedSnapshot {
+  Arc::new(ArcSwap::from_pointee(BookSnapshot::new(book)))
+}
+
+// readers see the prices on the common tick of the venues
+pub fn publish(shared: &SharedSnapshot, book: &AggregatedBook) {
+  shared.store(Arc::new(BookSnapshot::new(book.top(SNAPSHOT_LEVELS).on_tick())));
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{AggregatedBook, Level, OrderSide};
+  use super::{publish, shared, SNAPSHOT_LEVELS};
+  use rust_decimal::Decimal;
+  use rust_decimal_macros::dec;
+
+  #[test]
+  fn publish_snapshots() {
+    let mut book = AggregatedBook::new(String::from("ethbtc"));
+    let snapshot = shared(book.clone());
+    let before = snapshot.load_full();
+    book.insert_level(OrderSide::Bid, Level { exchange: String::from("binance"), price: dec!(0.07), amount: dec!(1) });
+    publish(&snapshot, &book);
+    assert!(before.summary.bids.is_empty());
+    assert_eq!(snapshot.load().summary.bids[0].price, dec!(0.07));
+
+    for i in 1..=SNAPSHOT_LEVELS + 10 {
+      book.insert_level(OrderSide::Ask, Level { exchange: String::from("binance"), price: Decimal::from(i), amount: dec!(1) });
+    }
+    publish(&snapshot, &book);
+    let published = snapshot.load();
+    assert_eq!(published.book.asks.len(), SNAPSHOT_LEVELS);
+    assert_eq!(published.book.asks.keys().next(), Some(&Decimal::from(1)));
+    assert_eq!(published.book.bids.len(), 1);
+  }
+}
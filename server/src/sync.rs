@@ -0,0 +1,201 @@
+use crate::exchange_tools::OrderBook;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+// messages kept while a venue waits for its snapshot, the oldest are dropped first
+pub const MAX_BUFFERED: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SyncState {
+  Connecting,
+  // the stream is open, its messages are buffered until the first snapshot
+  AwaitingSnapshot,
+  Synced,
+  // a gap or resync request, messages are buffered until the next snapshot
+  Resyncing,
+}
+
+// update ids covered by a venue message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sequence {
+  pub first: i64,
+  pub last: i64,
+}
+
+impl Sequence {
+  pub fn new(first: i64, last: i64) -> Self {
+    Self { first, last }
+  }
+}
+
+// what the connector does with a message or snapshot
+#[derive(Debug)]
+pub enum Outcome {
+  // books to merge, in order
+  Merge(Vec<OrderBook>),
+  Buffered,
+  // already covered by the snapshot or an earlier message
+  Outdated,
+  // an update id is missing, a new snapshot is needed
+  Gap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VenueStatus {
+  pub state: SyncState,
+  pub last_update_id: i64,
+  pub gaps: u64,
+  pub resyncs: u64,
+  pub buffered: usize,
+}
+
+// sync state of one venue stream. Messages of a contiguous venue are diffs,
+// each has to start right after the last one, other venues send whole books
+// and only have to be newer
+pub struct VenueSync {
+  state: SyncState,
+  contiguous: bool,
+  last_id: i64,
+  buffer: VecDeque<(Option<Sequence>, OrderBook)>,
+  gaps: u64,
+  resyncs: u64,
+}
+
+impl VenueSync {
+  pub fn new(contiguous: bool) -> Self {
+    Self {
+      state: SyncState::Connecting,
+      contiguous,
+      last_id: i64::MIN,
+      buffer: VecDeque::new(),
+      gaps: 0,
+      resyncs: 0,
+    }
+  }
+
+  pub fn state(&self) -> SyncState {
+    self.state
+  }
+
+  // messages are diffs, not whole books
+  pub fn contiguous(&self) -> bool {
+    self.contiguous
+  }
+
+  pub fn status(&self) -> VenueStatus {
+    VenueStatus {
+      state: self.state,
+      last_update_id: self.last_id.max(0),
+      gaps: self.gaps,
+      resyncs: self.resyncs,
+      buffered: self.buffer.len(),
+    }
+  }
+
+  pub fn connecting(&mut self) {
+    self.state = SyncState::Connecting;
+    self.buffer.clear();
+  }
+
+  pub fn connected(&mut self) {
+    self.state = SyncState::AwaitingSnapshot;
+  }
+
+  // stops merging until the next snapshot
+  pub fn resync(&mut self) {
+    if self.state == SyncState::Synced {
+      self.resyncs += 1;
+      self.state = SyncState::Resyncing;
+    }
+  }
+
+  fn buffer(&mut self, sequence: Option<Sequence>, book: OrderBook) {
+    if self.buffer.len() == MAX_BUFFERED {
+      self.buffer.pop_front();
+    }
+    self.buffer.push_back((sequence, book));
+  }
+
+  // a message without a sequence is merged as soon as the venue is synced
+  pub fn on_message(&mut self, sequence: Option<Sequence>, book: OrderBook) -> Outcome {
+    if self.state != SyncState::Synced {
+      self.buffer(sequence, book);
+      return Outcome::Buffered;
+    }
+    match sequence {
+      Some(s) if s.last <= self.last_id => Outcome::Outdated,
+      Some(s) if self.contiguous && s.first > self.last_id + 1 => {
+        println!("gap after update {}, next update starts at {}", self.last_id, s.first);
+        self.gaps += 1;
+        self.resync();
+        self.buffer(sequence, book);
+        Outcome::Gap
+      },
+      Some(s) => {
+        self.last_id = s.last;
+        Outcome::Merge(vec![book])
+      },
+      None => Outcome::Merge(vec![book]),
+    }
+  }
+
+  // replays the buffer on top of a snapshot taken at update `id`, None for
+  // venues without snapshots. A snapshot older than the buffered diffs is a gap
+  pub fn on_snapshot(&mut self, id: Option<i64>) -> Outcome {
+    let mut last_id = id.unwrap_or(self.last_id);
+    let mut books = Vec::new();
+    for (sequence, book) in self.buffer.iter() {
+      match sequence {
+        Some(s) if s.last <= last_id => continue,
+        Some(s) if self.contiguous && id.is_some() && s.first > last_id + 1 => return Outcome::Gap,
+        Some(s) => {
+          last_id = s.last;
+          books.push(book.clone());
+        },
+        None => books.push(book.clone()),
+      }
+    }
+    self.buffer.clear();
+    self.last_id = last_id;
+    self.state = SyncState::Synced;
+    Outcome::Merge(books)
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{OrderBook, VenueUpdate};
+  use super::{Outcome, Sequence, SyncState, VenueSync};
+
+  fn book() -> OrderBook {
+    OrderBook { exchange: String::from("binance"), bids: vec![], asks: vec![], update: VenueUpdate::default() }
+  }
+
+  fn merged(outcome: Outcome) -> usize {
+    match outcome {
+      Outcome::Merge(books) => books.len(),
+      other => panic!("nothing merged: {:?}", other),
+    }
+  }
+
+  #[test]
+  fn gaps_and_resyncs() {
+    let mut venue = VenueSync::new(true);
+    venue.connected();
+    assert!(matches!(venue.on_message(Some(Sequence::new(95, 99)), book()), Outcome::Buffered));
+    assert!(matches!(venue.on_message(Some(Sequence::new(100, 104)), book()), Outcome::Buffered));
+    // a snapshot older than the buffer can not be used
+    assert!(matches!(venue.on_snapshot(Some(90)), Outcome::Gap));
+    assert_eq!(venue.state(), SyncState::AwaitingSnapshot);
+    assert_eq!(merged(venue.on_snapshot(Some(101))), 1);
+    assert_eq!(venue.state(), SyncState::Synced);
+    assert!(matches!(venue.on_message(Some(Sequence::new(100, 104)), book()), Outcome::Outdated));
+    assert_eq!(merged(venue.on_message(Some(Sequence::new(105, 107)), book())), 1);
+    assert!(matches!(venue.on_message(Some(Sequence::new(110, 112)), book()), Outcome::Gap));
+    assert!(matches!(venue.on_message(Some(Sequence::new(113, 115)), book()), Outcome::Buffered));
+    let status = venue.status();
+    assert_eq!((status.state, status.gaps, status.resyncs, status.buffered), (SyncState::Resyncing, 1, 1, 2));
+    assert_eq!(merged(venue.on_snapshot(Some(111))), 2);
+    assert_eq!(venue.status().last_update_id, 115);
+  }
+}
@@ -0,0 +1,206 @@
+use crate::exchange_tools::{AggregatedBook, Level, OrderSide};
+use crate::instruments::split_pair;
+use crate::metrics;
+use crate::snapshot::SharedSnapshot;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+pub const SYNTHETIC_EXCHANGE: &str = "synthetic";
+
+// (price, amount) from the best price outwards
+type Ladder = Vec<(Decimal, Decimal)>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leg {
+  pub pair: String,
+  // the leg book is read as quote/base, i.e. ethbtc used as btceth
+  pub inverted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticDefinition {
+  pub symbol: String,
+  pub legs: Vec<Leg>,
+  // cumulative base amount per side of the synthetic book
+  pub max_quantity: Option<Decimal>,
+}
+
+// orients the legs so that the quote of a leg is the base of the next one
+fn chain(symbol: &str, pairs: &[&str]) -> Option<Vec<Leg>> {
+  let (base, quote) = split_pair(symbol);
+  let mut current = base;
+  let mut legs = Vec::new();
+  for pair in pairs {
+    let (leg_base, leg_quote) = split_pair(pair);
+    let pair = pair.to_ascii_lowercase();
+    if leg_base == current {
+      legs.push(Leg { pair, inverted: false });
+      current = leg_quote;
+    } else if leg_quote == current {
+      legs.push(Leg { pair, inverted: true });
+      current = leg_base;
+    } else {
+      return None;
+    }
+  }
+  if legs.is_empty() || current != quote { None } else { Some(legs) }
+}
+
+// "symbol=leg,leg[@max_quantity]", i.e. ethusdt=ethbtc,btcusdt@10
+pub fn parse_synthetic(value: &str) -> Option<SyntheticDefinition> {
+  let (symbol, spec) = value.split_once('=')?;
+  let (pairs, max_quantity) = match spec.split_once('@') {
+    Some((pairs, max)) => (pairs, Some(Decimal::from_str(max).ok()?)),
+    None => (spec, None),
+  };
+  let pairs: Vec<&str> = pairs.split(',').map(|p| p.trim()).collect();
+  Some(SyntheticDefinition {
+    symbol: symbol.to_ascii_lowercase(),
+    legs: chain(symbol, &pairs)?,
+    max_quantity,
+  })
+}
+
+// quote/base ladder of a base/quote ladder, amounts become quote amounts
+fn invert(ladder: Ladder) -> Ladder {
+  ladder.into_iter()
+    .filter(|l| !l.0.is_zero())
+    .map(|(price, amount)| (dec!(1) / price, amount * price))
+    .collect()
+}
+
+// x/z ladder of an x/y and a y/z ladder of the same side, every synthetic
+// level is limited by what is left on both legs
+fn compose(first: &Ladder, second: &Ladder) -> Ladder {
+  let mut ladder: Ladder = Vec::new();
+  let (mut i, mut j) = (0, 0);
+  let (mut left_first, mut left_second) = match (first.first(), second.first()) {
+    (Some(a), Some(b)) => (a.1, b.1),
+    _ => return ladder,
+  };
+  while i < first.len() && j < second.len() {
+    let (price_first, price_second) = (first[i].0, second[j].0);
+    let needed = left_first * price_first;
+    let amount = if needed <= left_second {
+      left_second -= needed;
+      let amount = left_first;
+      left_first = dec!(0);
+      amount
+    } else {
+      let amount = left_second / price_first;
+      left_first -= amount;
+      left_second = dec!(0);
+      amount
+    };
+    let price = price_first * price_second;
+    if matches!(ladder.last(), Some(last) if last.0 == price) {
+      ladder.last_mut().unwrap().1 += amount;
+    } else if amount > dec!(0) {
+      ladder.push((price, amount));
+    }
+    if left_first <= dec!(0) {
+      i += 1;
+      left_first = first.get(i).map(|l| l.1).unwrap_or(dec!(0));
+    }
+    if left_second <= dec!(0) {
+      j += 1;
+      left_second = second.get(j).map(|l| l.1).unwrap_or(dec!(0));
+    }
+  }
+  ladder
+}
+
+fn cap(ladder: Ladder, max_quantity: Option<Decimal>) -> Ladder {
+  let mut left = match max_quantity {
+    Some(max) => max,
+    None => return ladder,
+  };
+  let mut capped = Vec::new();
+  for (price, amount) in ladder {
+    if left <= dec!(0) {
+      break;
+    }
+    capped.push((price, amount.min(left)));
+    left -= amount;
+  }
+  capped
+}
+
+// synthetic book from the books of the legs, given in the order of the definition
+pub fn build(definition: &SyntheticDefinition, legs: &[&AggregatedBook]) -> AggregatedBook {
+  let mut book = AggregatedBook::new(definition.symbol.clone());
+  let mut ladders: Option<(Ladder, Ladder)> = None;
+  for (leg, leg_book) in definition.legs.iter().zip(legs) {
+    let (bids, asks) = (leg_book.price_levels(OrderSide::Bid), leg_book.price_levels(OrderSide::Ask));
+    let (bids, asks) = if leg.inverted { (invert(asks), invert(bids)) } else { (bids, asks) };
+    ladders = Some(match ladders {
+      Some((prev_bids, prev_asks)) => (compose(&prev_bids, &bids), compose(&prev_asks, &asks)),
+      None => (bids, asks),
+    });
+  }
+  if let Some(first) = legs.first() {
+    book.metrics_config = first.metrics_config.clone();
+  }
+  let (bids, asks) = ladders.unwrap_or_default();
+  for (side, ladder) in [(OrderSide::Bid, bids), (OrderSide::Ask, asks)] {
+    for (price, amount) in cap(ladder, definition.max_quantity) {
+      book.insert_level(side, Level { exchange: String::from(SYNTHETIC_EXCHANGE), price, amount });
+    }
+  }
+  if let (Some(ask), Some(bid)) = (book.asks.first_key_value(), book.bids.last_key_value()) {
+    book.spread = ask.0 - bid.0;
+  }
+  book.metrics = metrics::compute(&book, &book.metrics_config);
+  book
+}
+
+// None while a leg is not served by this server
+pub fn build_from(definition: &SyntheticDefinition, books: &HashMap<String, SharedSnapshot>) -> Option<AggregatedBook> {
+  let snapshots = definition.legs.iter()
+    .map(|leg| books.get(&leg.pair).map(|snapshot| snapshot.load_full()))
+    .collect::<Option<Vec<_>>>()?;
+  let legs: Vec<&AggregatedBook> = snapshots.iter().map(|snapshot| &snapshot.book).collect();
+  Some(build(definition, &legs))
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{AggregatedBook, Level, OrderSide};
+  use super::{build, parse_synthetic};
+  use rust_decimal_macros::dec;
+
+  fn book(pair: &str, bids: &[(rust_decimal::Decimal, rust_decimal::Decimal)], asks: &[(rust_decimal::Decimal, rust_decimal::Decimal)]) -> AggregatedBook {
+    let mut book = AggregatedBook::new(pair.to_owned());
+    for (side, levels) in [(OrderSide::Bid, bids), (OrderSide::Ask, asks)] {
+      for (price, amount) in levels {
+        book.insert_level(side, Level { exchange: String::from("binance"), price: *price, amount: *amount });
+      }
+    }
+    book
+  }
+
+  #[test]
+  fn triangulated_book() {
+    assert!(parse_synthetic("ethusdt=ethbtc,ltcusdt").is_none());
+    let definition = parse_synthetic("ethusdt=ethbtc,btcusdt").unwrap();
+    assert!(definition.legs.iter().all(|l| !l.inverted));
+
+    let ethbtc = book("ethbtc", &[(dec!(0.07), dec!(2))], &[(dec!(0.071), dec!(1))]);
+    let btcusdt = book("btcusdt", &[(dec!(30000), dec!(0.07)), (dec!(29000), dec!(1))], &[(dec!(30010), dec!(1))]);
+    let ethusdt = build(&definition, &[&ethbtc, &btcusdt]);
+    let bids = ethusdt.price_levels(OrderSide::Bid);
+    // 0.07 btc at 30000 covers one eth, the next eth goes into the 29000 level
+    assert_eq!(bids, vec![(dec!(2100), dec!(1)), (dec!(2030), dec!(1))]);
+    let asks = ethusdt.price_levels(OrderSide::Ask);
+    assert_eq!(asks, vec![(dec!(2130.71), dec!(1))]);
+
+    let inverted = parse_synthetic("btceth=ethbtc@0.05").unwrap();
+    assert!(inverted.legs[0].inverted);
+    let btceth = build(&inverted, &[&ethbtc]);
+    let bids = btceth.price_levels(OrderSide::Bid);
+    assert_eq!(bids[0].0, dec!(1) / dec!(0.071));
+    assert_eq!(bids[0].1, dec!(0.05));
+  }
+}
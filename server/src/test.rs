@@ -1,6 +1,6 @@
 use async_stream::stream;
-use crate::binance::OrderBook;
-use crate::client::error::Error;
+use crate::binance::{DepthUpdate, OrderBook};
+use crate::error::Error;
 use futures_util::{pin_mut, StreamExt, SinkExt};
 use itertools::Itertools;
 use rust_decimal_macros::dec;
@@ -8,7 +8,8 @@ use rust_decimal::Decimal;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
 
-async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) {
+// sends 100 books, or binance diffs with consecutive update ids
+async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr, diffs: bool) {
   println!("Incoming TCP connection from: {}", addr);
 
   let ws_stream = tokio_tungstenite::accept_async(raw_stream)
@@ -19,11 +20,16 @@ async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) {
   let (mut outgoing, _incoming) = ws_stream.split();
   let out_stream = stream! {
     for j in 0..100 {
-      let msg = serde_json::to_string(&OrderBook { 
-        lastUpdateId: dec!(1), 
-            asks: (1..16).map(|i| (Decimal::from_str_exact(&(100.0 + i as f64).to_string()).unwrap(), Decimal::from(j*j))).collect_vec(),
-            bids: (1..16).map(|i| (Decimal::from_str_exact(&(100.0 - i as f64).to_string()).unwrap(), Decimal::from(2 * j*j))).collect_vec(),
-           }).unwrap();
+      let asks = (1..16).map(|i| (Decimal::from_str_exact(&(100.0 + i as f64).to_string()).unwrap(), Decimal::from(j*j))).collect_vec();
+      let bids = (1..16).map(|i| (Decimal::from_str_exact(&(100.0 - i as f64).to_string()).unwrap(), Decimal::from(2 * j*j))).collect_vec();
+      let id = 10 * j as i64;
+      let msg = if diffs {
+        serde_json::to_string(&DepthUpdate {
+          e: String::from("depthUpdate"), E: 0, s: String::from("ETHBTC"), U: id + 1, u: id + 10, b: bids, a: asks,
+        })
+      } else {
+        serde_json::to_string(&OrderBook { lastUpdateId: dec!(1), asks, bids })
+      }.unwrap();
         yield msg;
       }
     };
@@ -33,13 +39,22 @@ async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) {
    }
 }
 
-// addr i.e 127.0.0.1:8080
-pub async fn server(addr: String) -> Result<(), Error> {
+async fn serve(addr: String, diffs: bool) -> Result<(), Error> {
   let try_socket = TcpListener::bind(&addr).await;
   let listener = try_socket.expect("Failed to bind");
   println!("Listening on: {}", addr);
   while let Ok((stream, addr)) = listener.accept().await {
-    tokio::spawn(handle_connection(stream, addr));
+    tokio::spawn(handle_connection(stream, addr, diffs));
   }
   Ok(())
-}
\ No newline at end of file
+}
+
+// addr i.e 127.0.0.1:8080
+pub async fn server(addr: String) -> Result<(), Error> {
+  serve(addr, false).await
+}
+
+// mock of the binance diff stream
+pub async fn diff_server(addr: String) -> Result<(), Error> {
+  serve(addr, true).await
+}
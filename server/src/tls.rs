@@ -0,0 +1,42 @@
+use crate::error::{Error, ServerError};
+use std::path::{Path, PathBuf};
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+// pem files of the server identity, plaintext is served without one
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+  pub cert: PathBuf,
+  pub key: PathBuf,
+  // clients have to present a certificate signed by this CA, any client connects without it
+  pub client_ca: Option<PathBuf>,
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, Error> {
+  std::fs::read(path)
+    .map_err(|e| ServerError::BadConfig(format!("can not read {}: {}", path.display(), e)).into())
+}
+
+impl TlsConfig {
+  pub fn server_config(&self) -> Result<ServerTlsConfig, Error> {
+    let identity = Identity::from_pem(read_pem(&self.cert)?, read_pem(&self.key)?);
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(ca) = &self.client_ca {
+      config = config.client_ca_root(Certificate::from_pem(read_pem(ca)?));
+    }
+    Ok(config)
+  }
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::error::{Error, ServerError};
+  use super::TlsConfig;
+
+  // loading certificates is covered by the mutual tls test of the server
+  #[test]
+  fn missing_pem() {
+    let dir = std::env::temp_dir().join("book-merger-missing-pem");
+    let config = TlsConfig { cert: dir.join("server.pem"), key: dir.join("server.key"), client_ca: None };
+    assert!(matches!(config.server_config(), Err(Error::Server(ServerError::BadConfig(_)))));
+  }
+}
@@ -0,0 +1,214 @@
+use crate::connector::{next_backoff, MIN_BACKOFF};
+use crate::error::{self, Error, ParseError};
+use crate::exchange_tools::{Exchange, Side};
+use crate::{binance, bitstamp};
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use num_traits::cast::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{self, Duration};
+use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use url::Url;
+
+pub const BINANCE_TRADES_WSS: &str = "wss://stream.binance.com:9443/ws/{}@trade";
+// milliseconds trades are held back to be merged in order
+pub const MERGE_WINDOW: i64 = 250;
+
+// a trade of any venue, times are milliseconds since the epoch
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+  pub exchange: String,
+  pub price: Decimal,
+  pub size: Decimal,
+  // side of the taker
+  pub side: Side,
+  pub trade_time: i64,
+  pub receive_time: i64,
+}
+
+pub fn parse_trade(exchange: &Exchange, message: &str) -> Result<Trade, Error> {
+  let receive_time = Utc::now().timestamp_millis();
+  match exchange {
+    Exchange::Bitstamp(_) => {
+      let event: bitstamp::TradeEvent = serde_json::from_str(message)?;
+      Ok(Trade {
+        exchange: String::from("bitstamp"),
+        price: event.data.price_str,
+        size: event.data.amount_str,
+        side: if event.data.kind == 0 { Side::Buy } else { Side::Sell },
+        trade_time: (event.data.microtimestamp / Decimal::from(1000)).to_i64().unwrap_or(receive_time),
+        receive_time,
+      })
+    },
+    _ => {
+      let trade: binance::Trade = serde_json::from_str(message)?;
+      Ok(Trade {
+        exchange: exchange.to_string(),
+        price: trade.p,
+        size: trade.q,
+        side: if trade.m { Side::Sell } else { Side::Buy },
+        trade_time: trade.T,
+        receive_time,
+      })
+    },
+  }
+}
+
+// holds every trade for `window` milliseconds after it was received, so that
+// trades of a slower venue are still released in trade time order. The window
+// runs on the local clock, venue clocks only order the trades
+pub struct TradeMerger {
+  window: i64,
+  pending: BTreeMap<(i64, u64), Trade>,
+  sequence: u64,
+  // trade time of the last released trade
+  released: i64,
+}
+
+impl TradeMerger {
+  pub fn new(window: i64) -> Self {
+    Self { window, pending: BTreeMap::new(), sequence: 0, released: i64::MIN }
+  }
+
+  // trade time of the last released trade, no later trade is older
+  pub fn released(&self) -> i64 {
+    self.released
+  }
+
+  // false for a trade older than one already released, it is dropped
+  pub fn push(&mut self, trade: Trade) -> bool {
+    if trade.trade_time < self.released {
+      return false;
+    }
+    self.sequence += 1;
+    self.pending.insert((trade.trade_time, self.sequence), trade);
+    true
+  }
+
+  // trades in trade time order up to the first one received less than the window before `now`
+  pub fn release(&mut self, now: i64) -> Vec<Trade> {
+    let mut released = Vec::new();
+    while let Some(entry) = self.pending.first_entry() {
+      if entry.get().receive_time > now.saturating_sub(self.window) {
+        break;
+      }
+      let trade = entry.remove();
+      self.released = trade.trade_time;
+      released.push(trade);
+    }
+    released
+  }
+}
+
+pub async fn connect_trades(exchange: Exchange, subscriber: Option<String>, tx: mpsc::Sender<Trade>) -> Result<(), Error> {
+  let url = Url::parse(&exchange.clone().value())?;
+  println!("connecting trades to {:?}", url);
+  let (ws_stream, _) = connect_async(url).await?;
+  let (mut out_stream, mut input_stream) = ws_stream.split();
+  if let Some(message) = subscriber {
+    out_stream.send(Message::Text(message)).await?;
+  }
+  while let Some(message) = input_stream.next().await {
+    let message = message?;
+    let parsed = match message.to_text() {
+      Ok(text) => parse_trade(&exchange, text),
+      Err(_) => Err(ParseError::NotText.into()),
+    };
+    match parsed {
+      Ok(trade) => if tx.send(trade).await.is_err() {
+        break;
+      },
+      Err(e) => error::recover(&format!("skipping {} trade", exchange.to_string()), &e),
+    }
+  }
+  Ok(())
+}
+
+// keeps the trade stream of the venue open, reconnecting with backoff until the merger is gone
+async fn stream_trades(exchange: Exchange, subscriber: Option<String>, tx: mpsc::Sender<Trade>) {
+  let mut backoff = MIN_BACKOFF;
+  while !tx.is_closed() {
+    match connect_trades(exchange.clone(), subscriber.clone(), tx.clone()).await {
+      Ok(()) => {
+        backoff = MIN_BACKOFF;
+        println!("{} trade stream closed, reconnecting in {:?}", exchange.to_string(), backoff);
+      },
+      Err(e) => error::recover(&format!("{} trade stream failed, reconnecting in {:?}", exchange.to_string(), backoff), &e),
+    }
+    time::sleep(backoff).await;
+    backoff = next_backoff(backoff);
+  }
+}
+
+// merges the trades of all venues and publishes them in trade time order,
+// `released` follows the trade time of the last published trade
+pub async fn merge_trades(
+  exchanges: Vec<(Exchange, Option<String>)>,
+  publisher: broadcast::Sender<Trade>,
+  released: watch::Sender<i64>) -> Result<(), Error> {
+  let (tx, mut rx) = mpsc::channel(1000);
+  for (exchange, subscriber) in exchanges {
+    tokio::spawn(stream_trades(exchange, subscriber, tx.clone()));
+  }
+  drop(tx);
+  let mut merger = TradeMerger::new(MERGE_WINDOW);
+  let mut interval = time::interval(Duration::from_millis(50));
+  loop {
+    tokio::select! {
+      trade = rx.recv() => match trade {
+        Some(trade) => {
+          let late = ParseError::LateTrade(trade.exchange.clone(), trade.trade_time);
+          if !merger.push(trade) {
+            error::recover("dropping trade", &late.into());
+          }
+        },
+        None => break,
+      },
+      _ = interval.tick() => {
+        for trade in merger.release(Utc::now().timestamp_millis()) {
+          // no receivers is fine, nobody subscribed yet
+          let _ = publisher.send(trade);
+        }
+        let time = merger.released();
+        released.send_if_modified(|last| std::mem::replace(last, time) != time);
+      },
+    }
+  }
+  for trade in merger.release(i64::MAX) {
+    let _ = publisher.send(trade);
+  }
+  released.send_replace(merger.released());
+  Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+  use crate::exchange_tools::{Exchange, Side};
+  use super::{parse_trade, Trade, TradeMerger};
+  use rust_decimal_macros::dec;
+
+  #[test]
+  fn merge_venue_trades() {
+    let binance = r#"{"e":"trade","E":1692535678125,"s":"ETHBTC","t":12345,"p":"0.06301","q":"1.5","T":1692535678120,"m":true}"#;
+    let bitstamp = r#"{"event":"trade","channel":"live_trades_ethbtc","data":{"id":1,"amount":0.2,"amount_str":"0.2","price":0.063,"price_str":"0.063","type":0,"timestamp":"1692535678","microtimestamp":"1692535678100000"}}"#;
+    let binance = parse_trade(&Exchange::Binance(String::new()), binance).unwrap();
+    let bitstamp = parse_trade(&Exchange::Bitstamp(String::new()), bitstamp).unwrap();
+    assert_eq!(binance.side, Side::Sell);
+    assert_eq!(binance.price, dec!(0.06301));
+    assert_eq!(bitstamp.side, Side::Buy);
+    assert_eq!(bitstamp.trade_time, 1692535678100);
+
+    // the earlier bitstamp trade arrives after the binance one
+    let mut merger = TradeMerger::new(100);
+    assert!(merger.push(Trade { receive_time: 1000, ..binance.clone() }));
+    assert!(merger.push(Trade { receive_time: 1050, ..bitstamp }));
+    assert!(merger.release(1120).is_empty());
+    let released = merger.release(1150);
+    assert_eq!(released.iter().map(|t| t.exchange.as_str()).collect::<Vec<_>>(), vec!["bitstamp", "binance"]);
+    assert_eq!(merger.released(), binance.trade_time);
+    // older than the released binance trade
+    assert!(!merger.push(Trade { trade_time: binance.trade_time - 1, receive_time: 1200, ..binance }));
+  }
+}
@@ -6,6 +6,9 @@ fn main() -> io::Result<()> {
   .build_client(true)
   .type_attribute("Summary", "#[derive(serde::Deserialize, serde::Serialize)]")
   .type_attribute("Level", "#[derive(serde::Deserialize, serde::Serialize)]")
+  .type_attribute("Metrics", "#[derive(serde::Deserialize, serde::Serialize)]")
+  .type_attribute("ConsolidatedLevel", "#[derive(serde::Deserialize, serde::Serialize)]")
+  .type_attribute("Venue", "#[derive(serde::Deserialize, serde::Serialize)]")
   .compile(&["../proto/orderbook.proto"], &["../proto"])
 }
   
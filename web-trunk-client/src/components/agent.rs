@@ -50,8 +50,8 @@ impl yew_agent::Worker for Worker {
         let base_url = "http://localhost:50051".to_string(); // URL of the gRPC-web server
         let c = Client::new(base_url);
         let mut query_client = OrderbookAggregatorClient::new(c); // `QueryClient` is the client generated by tonic
-        let empty_msg = proto::Empty {};
-        match query_client.book_summary(empty_msg).await {
+        let summary_request = proto::SummaryRequest::default();
+        match query_client.book_summary(summary_request).await {
           Ok(response) => {
             let mut stream_grpc = response.into_inner();
             while let Some(res) = stream_grpc.message().await.unwrap() {
@@ -4,7 +4,8 @@ use std::rc::Rc;
 pub mod components;
 use wasm_bindgen::JsValue;
 
-use crate::components::agent::proto::Level;
+use crate::components::agent::proto::{Level, SyncState};
+use std::collections::HashSet;
 
 use crate::components::{ 
   Worker, 
@@ -16,6 +17,11 @@ use crate::components::{
 };
 
 use yew_agent::Bridged;
+// exact decimal of the server, the double for servers without one
+fn exact(value: &str, rounded: f64) -> String {
+  if value.is_empty() { rounded.to_string() } else { value.to_owned() }
+}
+
 pub enum Msg {
   AgentReady(WorkerOutput),
   Redraw,
@@ -35,25 +41,25 @@ enum OrderBookSide {
 }
 
 impl OrderWeb {
-  fn update_spread(&self, spread: f64) ->  Result<(), JsValue> {
+  fn update_spread(&self, spread: &str) ->  Result<(), JsValue> {
     let selement = self.spread.cast::<web_sys::Element>().unwrap();
-    selement.set_text_content(Some(&spread.to_string()));
+    selement.set_text_content(Some(spread));
     Ok(())
   }
     
-  fn update_table(&self, levels: &Vec<Level>, side: OrderBookSide) -> Result<(), JsValue> {
+  // levels of venues in `dropped` are greyed out
+  fn update_table(&self, levels: &[Level], side: OrderBookSide, dropped: &HashSet<String>) -> Result<(), JsValue> {
     let table = match side {
         OrderBookSide::Ask => &self.ask_table,
         OrderBookSide::Bid => &self.bid_table,
     };
-    let mut row_idx = 0;
-    for level in levels {
-      let properties = vec![level.price.to_string(), level.amount.to_string(), level.exchange.clone()]; 
+    for (row_idx, level) in levels.iter().enumerate() {
+      let properties = [exact(&level.price_str, level.price), exact(&level.amount_str, level.amount), level.exchange.clone()]; 
       for column_idx in 0..3 {
         let td = (table[row_idx])[column_idx].cast::<web_sys::Element>().unwrap();
         td.set_text_content(Some(&properties[column_idx]));
+        td.set_class_name(if dropped.contains(&level.exchange) { "dropped" } else { "" });
       } 
-      row_idx +=1;
     }
       Ok(())
     }
@@ -129,9 +135,13 @@ impl OrderWeb {
       Self::Message::AgentReady(response) => {
          //log::info!("agent response: {:?}", response);
          if let Some(latest) = response.book.last() {
-          let _ = self.update_table(&latest.asks, OrderBookSide::Ask);
-          let _= self.update_table(&latest.bids, OrderBookSide::Bid);
-          let _ = self.update_spread(latest.spread);
+          let dropped: HashSet<String> = latest.venues.iter()
+            .filter(|v| v.state != SyncState::Synced as i32)
+            .map(|v| v.exchange.clone())
+            .collect();
+          let _ = self.update_table(&latest.asks, OrderBookSide::Ask, &dropped);
+          let _= self.update_table(&latest.bids, OrderBookSide::Bid, &dropped);
+          let _ = self.update_spread(&exact(&latest.spread_str, latest.spread));
          }         
         true
     },